@@ -1,13 +1,25 @@
+pub mod resources;
+
 use std::collections::BTreeMap;
 
+use resources::{quantity_to_mebibytes, Resources};
 use serde::{Deserialize, Serialize};
 use snafu::{OptionExt, Snafu};
 use stackable_operator::{
     crd::ClusterRef,
+    k8s_openapi::{
+        api::core::v1::Affinity,
+        apimachinery::pkg::{api::resource::Quantity, apis::meta::v1::Condition},
+    },
     kube::{runtime::reflector::ObjectRef, CustomResource},
     product_config_utils::{ConfigError, Configuration},
     role_utils::{Role, RoleGroupRef},
-    schemars::{self, JsonSchema},
+    schemars::{
+        self,
+        gen::SchemaGenerator,
+        schema::{InstanceType, Schema, SchemaObject},
+        JsonSchema,
+    },
 };
 
 /// A cluster of ZooKeeper nodes
@@ -31,11 +43,184 @@ pub struct ZookeeperClusterSpec {
     /// Emergency stop button, if `true` then all pods are stopped without affecting configuration (as setting `replicas` to `0` would)
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub stopped: Option<bool>,
+    /// Stop the operator from changing anything about the cluster, for example during manual maintenance
+    ///
+    /// The status is still kept up to date while reconciliation is paused.
+    #[serde(default)]
+    pub reconciliation_paused: bool,
     /// Desired ZooKeeper version
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub version: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub servers: Option<Role<ZookeeperConfig>>,
+    /// TLS encryption settings, all traffic is unencrypted if this is not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<ZookeeperClusterTls>,
+    /// How clients authenticate themselves, clients are anonymous if this is not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authentication: Option<ZookeeperAuthentication>,
+    /// The `PodDisruptionBudget` that limits how many servers may be evicted at the same time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pod_disruption_budget: Option<PodDisruptionBudgetConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodDisruptionBudgetConfig {
+    /// Whether to create a `PodDisruptionBudget` for the servers
+    #[serde(default = "PodDisruptionBudgetConfig::default_enabled")]
+    pub enabled: bool,
+    /// The number of servers that may be unavailable at the same time
+    ///
+    /// Defaults to the number of servers that the ensemble can lose while keeping its quorum. No `PodDisruptionBudget`
+    /// is created by default for ensembles of less than 3 servers, since they cannot lose any server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_unavailable: Option<u16>,
+}
+
+impl PodDisruptionBudgetConfig {
+    fn default_enabled() -> bool {
+        true
+    }
+}
+
+impl Default for PodDisruptionBudgetConfig {
+    fn default() -> Self {
+        Self {
+            enabled: Self::default_enabled(),
+            max_unavailable: None,
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ZookeeperAuthentication {
+    /// Authenticate clients using SASL with the DIGEST-MD5 mechanism
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<DigestAuthentication>,
+    /// Authenticate clients using SASL with the GSSAPI (Kerberos) mechanism
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kerberos: Option<KerberosAuthentication>,
+}
+
+#[derive(Clone, Default, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DigestAuthentication {
+    /// The name of a `Secret` in the same namespace as the `ZookeeperCluster`, whose keys are the usernames and whose
+    /// values are the corresponding passwords
+    pub credentials_secret_name: String,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KerberosAuthentication {
+    /// The Kerberos realm that the server principals belong to
+    pub realm: String,
+    /// The name of a `Secret` in the same namespace as the `ZookeeperCluster`, whose key `keytab` contains the keys
+    /// of all server principals
+    pub keytab_secret_name: String,
+    /// The name of a `ConfigMap` in the same namespace as the `ZookeeperCluster`, whose key `krb5.conf` contains the
+    /// Kerberos client configuration
+    pub krb5_config_map_name: String,
+    /// The primary component of the server principals, which are of the form `{serviceName}/{pod FQDN}@{realm}`
+    #[serde(default = "KerberosAuthentication::default_service_name")]
+    pub service_name: String,
+    /// Strip the host component from client principals before using them as ACL ids
+    #[serde(default)]
+    pub remove_host_from_principal: bool,
+    /// Strip the realm from client principals before using them as ACL ids
+    #[serde(default)]
+    pub remove_realm_from_principal: bool,
+}
+
+impl KerberosAuthentication {
+    pub const KEYTAB_KEY: &'static str = "keytab";
+    pub const KRB5_CONF_KEY: &'static str = "krb5.conf";
+
+    fn default_service_name() -> String {
+        "zookeeper".to_string()
+    }
+
+    /// Placeholder for the pod FQDN in server principals, which is filled in when the pod starts
+    pub const HOST_PLACEHOLDER: &'static str = "_HOST";
+
+    /// The principal that the server running on `host` authenticates as
+    pub fn server_principal(&self, host: &str) -> String {
+        format!("{}/{}@{}", self.service_name, host, self.realm)
+    }
+}
+
+#[derive(Clone, Default, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ZookeeperClusterTls {
+    /// Require clients to connect over TLS
+    ///
+    /// The plaintext client port is disabled while this is set, and the `secureClientPort` is exposed instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<TlsSecretRef>,
+    /// Encrypt the traffic between the ZooKeeper servers (leader election and replication)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quorum: Option<QuorumTls>,
+}
+
+#[derive(Clone, Default, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuorumTls {
+    #[serde(flatten)]
+    pub secret: TlsSecretRef,
+    /// How strictly TLS is enforced between servers
+    ///
+    /// Existing plaintext ensembles can be migrated without downtime by stepping through
+    /// `Accept`, `Prefer` and finally `Require`, waiting for each rollout to complete before moving on.
+    #[serde(default)]
+    pub mode: QuorumTlsMode,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+pub enum QuorumTlsMode {
+    /// Servers accept both TLS and plaintext connections from their peers, but still connect to them in plaintext
+    Accept,
+    /// Servers connect to their peers using TLS, but still accept plaintext connections from servers that have not been migrated yet
+    Prefer,
+    /// Servers only use TLS to talk to each other
+    Require,
+}
+
+impl Default for QuorumTlsMode {
+    fn default() -> Self {
+        Self::Require
+    }
+}
+
+impl QuorumTlsMode {
+    /// Whether servers connect to each other using TLS (`sslQuorum`)
+    pub fn ssl_quorum(self) -> bool {
+        !matches!(self, Self::Accept)
+    }
+
+    /// Whether servers also accept connections that use the other protocol (`portUnification`)
+    pub fn port_unification(self) -> bool {
+        !matches!(self, Self::Require)
+    }
+}
+
+/// Reference to a `Secret` containing the key material used for TLS
+///
+/// The `Secret` must contain the PKCS#12 stores `keystore.p12` and `truststore.p12`, as well as the key `password`,
+/// which is used to unlock both stores.
+#[derive(Clone, Default, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsSecretRef {
+    /// The name of the `Secret`, which must be in the same namespace as the `ZookeeperCluster`
+    pub secret_name: String,
+}
+
+impl TlsSecretRef {
+    pub const KEYSTORE_FILE: &'static str = "keystore.p12";
+    pub const TRUSTSTORE_FILE: &'static str = "truststore.p12";
+    pub const PASSWORD_KEY: &'static str = "password";
+    pub const STORE_TYPE: &'static str = "PKCS12";
 }
 
 #[derive(Clone, Default, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
@@ -45,6 +230,62 @@ pub struct ZookeeperConfig {
     pub sync_limit: Option<u32>,
     pub tick_time: Option<u32>,
     pub myid_offset: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resources: Option<Resources>,
+    /// Extra arguments for the ZooKeeper JVM, which are added after the operator's own flags
+    ///
+    /// Role- and rolegroup-level arguments are combined, rather than overriding each other.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub jvm_arguments: Vec<String>,
+    /// The affinity of the server pods
+    ///
+    /// Defaults to a soft anti-affinity between all servers of the role, so that they are scheduled onto different
+    /// nodes where possible.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub affinity: Option<Affinity>,
+    /// Spreads the servers of the role evenly across zones (`topology.kubernetes.io/zone`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone_spread: Option<ZoneSpread>,
+    /// A partial pod template that is merged onto the one generated by the operator
+    ///
+    /// Rolegroup-level overrides are applied after the role-level ones. Lists of named objects (such as `containers`,
+    /// `volumes` and `env`) are merged by name, other lists replace the generated ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(schema_with = "pod_overrides_schema")]
+    pub pod_overrides: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+pub enum ZoneSpread {
+    /// Servers are spread across zones where possible, but may still be scheduled if the zones are unbalanced
+    Preferred,
+    /// Servers are not scheduled if that would unbalance the zones by more than one server
+    Required,
+}
+
+impl ZoneSpread {
+    /// The `whenUnsatisfiable` policy of the topology spread constraint
+    pub fn when_unsatisfiable(self) -> &'static str {
+        match self {
+            Self::Preferred => "ScheduleAnyway",
+            Self::Required => "DoNotSchedule",
+        }
+    }
+}
+
+/// Accepts any object, since partial overrides leave out fields (such as `containers`) that `PodTemplateSpec` requires,
+/// both in its schema and when deserializing it
+fn pod_overrides_schema(_: &mut SchemaGenerator) -> Schema {
+    Schema::Object(SchemaObject {
+        instance_type: Some(InstanceType::Object.into()),
+        extensions: [(
+            "x-kubernetes-preserve-unknown-fields".to_string(),
+            true.into(),
+        )]
+        .into_iter()
+        .collect(),
+        ..SchemaObject::default()
+    })
 }
 
 impl ZookeeperConfig {
@@ -55,9 +296,45 @@ impl ZookeeperConfig {
     pub const MYID_OFFSET: &'static str = "MYID_OFFSET";
     pub const SERVER_JVMFLAGS: &'static str = "SERVER_JVMFLAGS";
 
+    pub const JAAS_FILE: &'static str = "jaas.conf";
+    /// A writable copy of the rolegroup `ConfigMap`, since ZooKeeper rewrites its config files when the membership
+    /// changes, and some files are filled in when the pod starts
+    pub const RW_CONFIG_DIR: &'static str = "/stackable/rwconfig";
+    pub const KEYTAB_DIR: &'static str = "/stackable/kerberos";
+    pub const KRB5_CONF_DIR: &'static str = "/stackable/kerberos-config";
+
+    /// The share of the memory limit that is given to the JVM heap, the rest is left for off-heap memory
+    const HEAP_FRACTION: f64 = 0.8;
+    /// The smallest memory limit that leaves ZooKeeper a usable heap, smaller limits are rejected
+    const MIN_MEMORY_LIMIT_MIB: u64 = 256;
+
     fn myid_offset(&self) -> u16 {
         self.myid_offset.unwrap_or(1)
     }
+
+    /// Fills in any fields that are unset in `self` from `defaults` (usually the role-level config)
+    ///
+    /// `myid_offset` is never inherited, since it must be unique for each rolegroup. `jvm_arguments` are
+    /// concatenated, with the arguments from `defaults` first. `pod_overrides` are not inherited either, since both
+    /// levels are applied to the pod template separately.
+    pub fn merge(&self, defaults: &Self) -> Self {
+        Self {
+            init_limit: self.init_limit.or(defaults.init_limit),
+            sync_limit: self.sync_limit.or(defaults.sync_limit),
+            tick_time: self.tick_time.or(defaults.tick_time),
+            myid_offset: self.myid_offset,
+            resources: resources::merge_opt(&self.resources, &defaults.resources, Resources::merge),
+            affinity: self.affinity.clone().or_else(|| defaults.affinity.clone()),
+            zone_spread: self.zone_spread.or(defaults.zone_spread),
+            jvm_arguments: defaults
+                .jvm_arguments
+                .iter()
+                .chain(&self.jvm_arguments)
+                .cloned()
+                .collect(),
+            pod_overrides: self.pod_overrides.clone(),
+        }
+    }
 }
 
 impl Configuration for ZookeeperConfig {
@@ -65,10 +342,53 @@ impl Configuration for ZookeeperConfig {
 
     fn compute_env(
         &self,
-        _resource: &Self::Configurable,
+        resource: &Self::Configurable,
         _role_name: &str,
     ) -> Result<BTreeMap<String, Option<String>>, ConfigError> {
-        let jvm_flags = "-javaagent:/stackable/jmx/jmx_prometheus_javaagent-0.16.1.jar=9505:/stackable/jmx/server.yaml".to_string();
+        let mut jvm_flags = "-javaagent:/stackable/jmx/jmx_prometheus_javaagent-0.16.1.jar=9505:/stackable/jmx/server.yaml".to_string();
+        if resource.sasl_authentication_enabled() {
+            // The digest passwords are filled in when the pod starts, so jaas.conf is not read from the ConfigMap
+            jvm_flags.push_str(&format!(
+                " -Djava.security.auth.login.config={}/{}",
+                Self::RW_CONFIG_DIR,
+                Self::JAAS_FILE
+            ));
+        }
+        if resource.kerberos_authentication().is_some() {
+            jvm_flags.push_str(&format!(
+                " -Djava.security.krb5.conf={}/{}",
+                Self::KRB5_CONF_DIR,
+                KerberosAuthentication::KRB5_CONF_KEY
+            ));
+        }
+        if let Some(memory_limit) = self
+            .resources
+            .as_ref()
+            .and_then(|resources| resources.memory.as_ref())
+            .and_then(|memory| memory.limit.as_ref())
+        {
+            let memory_limit_mib = quantity_to_mebibytes(memory_limit).map_err(|err| {
+                ConfigError::InvalidConfiguration {
+                    reason: format!("invalid memory limit: {}", err),
+                }
+            })?;
+            if memory_limit_mib < Self::MIN_MEMORY_LIMIT_MIB {
+                return Err(ConfigError::InvalidConfiguration {
+                    reason: format!(
+                        "memory limit {} is below the minimum of {}Mi",
+                        memory_limit.0,
+                        Self::MIN_MEMORY_LIMIT_MIB
+                    ),
+                });
+            }
+            let heap_mib = (memory_limit_mib as f64 * Self::HEAP_FRACTION) as u64;
+            jvm_flags.push_str(&format!(" -Xmx{heap}m -Xms{heap}m", heap = heap_mib));
+        }
+        // Added last, so that users can override the operator's flags (the JVM uses the last occurrence)
+        for arg in &self.jvm_arguments {
+            jvm_flags.push(' ');
+            jvm_flags.push_str(arg);
+        }
         Ok([
             (
                 Self::MYID_OFFSET.to_string(),
@@ -119,6 +439,79 @@ pub struct ZookeeperClusterStatus {
     /// An opaque value that changes every time a discovery detail does
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub discovery_hash: Option<String>,
+    /// Volumes that are being resized, since their requested capacity was increased
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volume_resizes: Vec<VolumeResizeStatus>,
+    /// The `metadata.generation` of the `ZookeeperCluster` that was last reconciled successfully
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    /// The standard conditions `Available`, `Progressing`, `Degraded` and `ReconciliationPaused`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
+    /// The name of the `Pod` that is currently the ensemble's leader
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leader: Option<String>,
+    /// The state of each server, as reported by the server itself
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub servers: Vec<ZookeeperServerStatus>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ZookeeperServerStatus {
+    pub pod_name: String,
+    pub myid: u16,
+    /// The server's role in the ensemble (`leader`, `follower`, `observer` or `standalone`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    /// The ID of the last transaction that the server has processed, in hexadecimal
+    ///
+    /// Followers whose zxid stays behind the leader's are lagging.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zxid: Option<String>,
+    /// The number of client requests that are queued up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outstanding_requests: Option<u64>,
+    /// The number of open client connections
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connections: Option<u64>,
+    /// The average latency of client requests, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avg_latency: Option<f64>,
+    /// The maximum latency of client requests, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_latency: Option<u64>,
+    /// The number of znodes in the server's data tree
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub znode_count: Option<u64>,
+    /// The number of followers that are in sync with the leader, only reported by the leader
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub synced_followers: Option<u64>,
+    /// The number of followers that are still syncing with the leader, only reported by the leader
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_syncs: Option<u64>,
+    /// Why the server could not be queried
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VolumeResizeStatus {
+    /// The name of the `PersistentVolumeClaim`
+    pub name: String,
+    pub requested_capacity: Quantity,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_capacity: Option<Quantity>,
+    pub state: VolumeResizeState,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+pub enum VolumeResizeState {
+    /// The volume is being expanded to the requested capacity
+    Resizing,
+    /// The volume's `StorageClass` does not allow expansion, so it keeps its current capacity
+    ExpansionNotSupported,
 }
 
 #[derive(Debug, Snafu)]
@@ -126,6 +519,31 @@ pub struct ZookeeperClusterStatus {
 pub struct NoNamespaceError;
 
 impl ZookeeperCluster {
+    /// The TLS settings for client connections, if clients are required to use TLS
+    pub fn client_tls(&self) -> Option<&TlsSecretRef> {
+        self.spec.tls.as_ref()?.client.as_ref()
+    }
+
+    /// The TLS settings for connections between servers, if quorum TLS is enabled
+    pub fn quorum_tls(&self) -> Option<&QuorumTls> {
+        self.spec.tls.as_ref()?.quorum.as_ref()
+    }
+
+    /// The settings for SASL/Digest client authentication, if enabled
+    pub fn digest_authentication(&self) -> Option<&DigestAuthentication> {
+        self.spec.authentication.as_ref()?.digest.as_ref()
+    }
+
+    /// The settings for SASL/Kerberos client authentication, if enabled
+    pub fn kerberos_authentication(&self) -> Option<&KerberosAuthentication> {
+        self.spec.authentication.as_ref()?.kerberos.as_ref()
+    }
+
+    /// Whether any SASL authentication mechanism is enabled
+    pub fn sasl_authentication_enabled(&self) -> bool {
+        self.digest_authentication().is_some() || self.kerberos_authentication().is_some()
+    }
+
     /// The name of the role-level load-balanced Kubernetes `Service`
     pub fn server_role_service_name(&self) -> Option<String> {
         self.metadata.name.clone()
@@ -152,6 +570,83 @@ impl ZookeeperCluster {
         }
     }
 
+    /// The config of a server rolegroup, with any unset fields inherited from the role-level config
+    pub fn merged_config(&self, rolegroup_name: &str) -> ZookeeperConfig {
+        let role = match &self.spec.servers {
+            Some(role) => role,
+            None => return ZookeeperConfig::default(),
+        };
+        role.role_groups
+            .get(rolegroup_name)
+            .map(|rolegroup| &rolegroup.config.config)
+            .unwrap_or(&ZookeeperConfig::default())
+            .merge(&role.config.config)
+    }
+
+    /// The server role, with each rolegroup's config merged with the role-level config (see [`Self::merged_config`])
+    ///
+    /// This should be used for generating the product config, since the product config only merges the computed
+    /// properties, so a rolegroup that sets any memory setting would otherwise lose the role-level JVM flags.
+    ///
+    /// `configOverrides` and `envOverrides` are left out, since they are applied after product config validation
+    /// (see [`Self::config_overrides`] and [`Self::env_overrides`]).
+    pub fn merged_server_role(&self) -> Option<Role<ZookeeperConfig>> {
+        let mut role = self.spec.servers.clone()?;
+        role.config.config_overrides.clear();
+        role.config.env_overrides.clear();
+        for rolegroup in role.role_groups.values_mut() {
+            rolegroup.config.config = rolegroup.config.config.merge(&role.config.config);
+            rolegroup.config.config_overrides.clear();
+            rolegroup.config.env_overrides.clear();
+        }
+        Some(role)
+    }
+
+    /// The `configOverrides` for `file` that apply to the rolegroup, rolegroup-level overrides take precedence over role-level ones
+    pub fn config_overrides(&self, rolegroup_name: &str, file: &str) -> BTreeMap<String, String> {
+        let role = match &self.spec.servers {
+            Some(role) => role,
+            None => return BTreeMap::new(),
+        };
+        let rolegroup = role.role_groups.get(rolegroup_name);
+        role.config
+            .config_overrides
+            .get(file)
+            .into_iter()
+            .chain(rolegroup.and_then(|rg| rg.config.config_overrides.get(file)))
+            .flatten()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// The `envOverrides` that apply to the rolegroup, rolegroup-level overrides take precedence over role-level ones
+    pub fn env_overrides(&self, rolegroup_name: &str) -> BTreeMap<String, String> {
+        let role = match &self.spec.servers {
+            Some(role) => role,
+            None => return BTreeMap::new(),
+        };
+        let rolegroup = role.role_groups.get(rolegroup_name);
+        role.config
+            .env_overrides
+            .iter()
+            .chain(
+                rolegroup
+                    .into_iter()
+                    .flat_map(|rg| &rg.config.env_overrides),
+            )
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// The number of servers that may be unavailable at the same time without losing quorum
+    ///
+    /// This is 0 for ensembles of less than three servers, since they cannot lose any server.
+    pub fn server_max_unavailable(&self) -> Result<u16, NoNamespaceError> {
+        let servers = self.pods()?.count();
+        let max_unavailable = servers.saturating_sub(1) / 2;
+        Ok(max_unavailable.try_into().unwrap_or(u16::MAX))
+    }
+
     /// List all pods expected to form the cluster
     ///
     /// We try to predict the pods here rather than looking at the current cluster state in order to
@@ -179,6 +674,30 @@ impl ZookeeperCluster {
                 })
             }))
     }
+
+    /// The server that would have the given `myid`, even if its rolegroup does not currently have enough replicas
+    ///
+    /// This finds the rolegroup with the largest `myidOffset` that is not above `myid`.
+    pub fn server_pod_by_myid(&self, myid: u16) -> Option<ZookeeperPodRef> {
+        let ns = self.metadata.namespace.clone()?;
+        let (rolegroup_name, offset) = self
+            .spec
+            .servers
+            .iter()
+            .flat_map(|role| &role.role_groups)
+            .map(|(rolegroup_name, rolegroup)| {
+                (rolegroup_name, rolegroup.config.config.myid_offset())
+            })
+            .filter(|(_, offset)| *offset <= myid)
+            .max_by_key(|(_, offset)| *offset)?;
+        let rolegroup_ref = self.server_rolegroup_ref(rolegroup_name);
+        Some(ZookeeperPodRef {
+            namespace: ns,
+            role_group_service_name: rolegroup_ref.object_name(),
+            pod_name: format!("{}-{}", rolegroup_ref.object_name(), myid - offset),
+            zookeeper_myid: myid,
+        })
+    }
 }
 
 /// Reference to a single `Pod` that is a component of a [`ZookeeperCluster`]
@@ -216,6 +735,7 @@ impl ZookeeperPodRef {
     plural = "zookeeperznodes",
     shortname = "zno",
     shortname = "znode",
+    status = "ZookeeperZnodeStatus",
     namespaced,
     crates(
         kube_core = "stackable_operator::kube::core",
@@ -227,4 +747,144 @@ impl ZookeeperPodRef {
 pub struct ZookeeperZnodeSpec {
     #[serde(default)]
     pub cluster_ref: ClusterRef<ZookeeperCluster>,
+    /// The access control list of the chroot ZNode, which is kept up to date when it changes
+    ///
+    /// Anyone may access the ZNode if this is empty. Note that ACLs are not inherited, so the application is responsible for
+    /// setting the ACLs of any ZNodes that it creates inside the chroot.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub acls: Vec<ZnodeAcl>,
+    /// Generate a random username and password that is granted full access to the chroot
+    ///
+    /// The credentials are written to a `Secret` with the same name as the `ZookeeperZnode`, containing the keys
+    /// `username`, `password` and `jaas.conf` (a ready-to-use JAAS configuration for the ZooKeeper client). If the
+    /// cluster uses SASL/Digest authentication, the user is also registered with its servers.
+    #[serde(default)]
+    pub generate_credentials: bool,
+    /// Stop the operator from changing anything about the ZNode, for example during manual maintenance
+    ///
+    /// This also delays the deletion of the `ZookeeperZnode` until reconciliation is resumed.
+    #[serde(default)]
+    pub reconciliation_paused: bool,
+}
+
+#[derive(Clone, Default, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ZookeeperZnodeStatus {
+    /// The path of the chroot ZNode inside the ZooKeeper cluster
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub znode_path: Option<String>,
+    /// The names of the `ConfigMap`s that contain the connection string for the chroot
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub discovery_config_maps: Vec<String>,
+    /// The standard `Ready` and `ReconciliationPaused` conditions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
+    /// The error of the last failed reconciliation, this is cleared once a reconciliation succeeds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// A single entry in the access control list of a ZNode
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ZnodeAcl {
+    pub scheme: ZnodeAclScheme,
+    /// The identity that is granted access, its format depends on the `scheme`
+    ///
+    /// - `digest`: `{username}:{base64(sha1("{username}:{password}"))}`
+    /// - `sasl`: the authenticated principal (such as `alice`, or `zookeeper/host@REALM`, depending on the server's
+    ///   `kerberos.remove*FromPrincipal` settings)
+    /// - `ip`: an IP address, or a CIDR block such as `10.0.0.0/8`
+    /// - `x509`: the distinguished name of the client certificate
+    pub id: String,
+    pub permissions: Vec<ZnodePermission>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Serialize, strum::Display)]
+#[serde(rename_all = "camelCase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ZnodeAclScheme {
+    Digest,
+    Sasl,
+    Ip,
+    X509,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ZnodePermission {
+    Read,
+    Write,
+    Create,
+    Delete,
+    Admin,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster(replicas: &[u16]) -> ZookeeperCluster {
+        let role_groups = replicas
+            .iter()
+            .enumerate()
+            .map(|(i, replicas)| {
+                (
+                    format!("group{}", i),
+                    serde_json::json!({ "replicas": replicas }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>();
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": "zookeeper.stackable.tech/v1alpha1",
+            "kind": "ZookeeperCluster",
+            "metadata": { "name": "simple-zk", "namespace": "default" },
+            "spec": { "servers": { "roleGroups": role_groups } },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn server_max_unavailable_keeps_quorum() {
+        for (replicas, expected) in [
+            (&[][..], 0),
+            (&[1], 0),
+            (&[2], 0),
+            (&[3], 1),
+            (&[4], 1),
+            (&[5], 2),
+            (&[2, 3], 2),
+            (&[7], 3),
+        ] {
+            assert_eq!(
+                cluster(replicas).server_max_unavailable().unwrap(),
+                expected,
+                "replicas: {:?}",
+                replicas
+            );
+        }
+    }
+
+    #[test]
+    fn heap_is_sized_by_memory_limit() {
+        let jvm_flags = |limit: &str| {
+            let config: ZookeeperConfig = serde_json::from_value(serde_json::json!({
+                "resources": { "memory": { "limit": limit } },
+            }))
+            .unwrap();
+            config
+                .compute_env(&cluster(&[3]), "server")
+                .map(|env| env[ZookeeperConfig::SERVER_JVMFLAGS].clone().unwrap())
+        };
+        assert!(jvm_flags("1Gi").unwrap().contains(" -Xmx819m -Xms819m"));
+        assert!(jvm_flags("256Mi").unwrap().contains(" -Xmx204m -Xms204m"));
+        assert!(matches!(
+            jvm_flags("255Mi"),
+            Err(ConfigError::InvalidConfiguration { .. })
+        ));
+        assert!(matches!(
+            jvm_flags("1"),
+            Err(ConfigError::InvalidConfiguration { .. })
+        ));
+    }
 }
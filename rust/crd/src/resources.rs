@@ -0,0 +1,277 @@
+//! Compute and storage resources that can be requested for ZooKeeper servers
+
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+use stackable_operator::{
+    k8s_openapi::apimachinery::pkg::{api::resource::Quantity, apis::meta::v1::LabelSelector},
+    schemars::{self, JsonSchema},
+};
+
+#[derive(Debug, Snafu)]
+pub enum QuantityError {
+    #[snafu(display("quantity {:?} has an unknown suffix", quantity))]
+    UnknownSuffix { quantity: String },
+    #[snafu(display("quantity {:?} is not a number", quantity))]
+    InvalidNumber {
+        source: std::num::ParseFloatError,
+        quantity: String,
+    },
+    #[snafu(display("quantity {:?} has an invalid exponent", quantity))]
+    InvalidExponent {
+        source: std::num::ParseIntError,
+        quantity: String,
+    },
+}
+
+#[derive(Clone, Default, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Resources {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<CpuResources>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<MemoryResources>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StorageResources>,
+}
+
+#[derive(Clone, Default, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CpuResources {
+    /// The amount of CPU that is reserved for each server (the container's request)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<Quantity>,
+    /// The amount of CPU that each server may use at most (the container's limit)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<Quantity>,
+}
+
+#[derive(Clone, Default, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryResources {
+    /// The amount of memory that is reserved for each server, and that it may not exceed
+    ///
+    /// The JVM heap is sized to fit within this limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<Quantity>,
+}
+
+#[derive(Clone, Default, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageResources {
+    /// The volume that ZooKeeper stores its snapshots (and transaction logs, unless `dataLog` is set) in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<PvcConfig>,
+    /// A dedicated volume for the transaction logs (`dataLogDir`)
+    ///
+    /// ZooKeeper recommends keeping the transaction logs on a dedicated device, since latency while writing them
+    /// directly affects the latency of every write request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_log: Option<PvcConfig>,
+}
+
+/// Settings for a `PersistentVolumeClaim` template
+#[derive(Clone, Default, Debug, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PvcConfig {
+    /// The size of the volume
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<Quantity>,
+    /// The `StorageClass` to provision the volume from, the cluster's default `StorageClass` is used if this is not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_class_name: Option<String>,
+    /// Only bind to existing `PersistentVolume`s that match this selector
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selectors: Option<LabelSelector>,
+}
+
+impl Resources {
+    /// Fills in any fields that are unset in `self` from `defaults`
+    pub fn merge(&self, defaults: &Self) -> Self {
+        Self {
+            cpu: merge_opt(&self.cpu, &defaults.cpu, CpuResources::merge),
+            memory: merge_opt(&self.memory, &defaults.memory, MemoryResources::merge),
+            storage: merge_opt(&self.storage, &defaults.storage, StorageResources::merge),
+        }
+    }
+}
+
+impl CpuResources {
+    fn merge(&self, defaults: &Self) -> Self {
+        Self {
+            min: self.min.clone().or_else(|| defaults.min.clone()),
+            max: self.max.clone().or_else(|| defaults.max.clone()),
+        }
+    }
+}
+
+impl MemoryResources {
+    fn merge(&self, defaults: &Self) -> Self {
+        Self {
+            limit: self.limit.clone().or_else(|| defaults.limit.clone()),
+        }
+    }
+}
+
+impl StorageResources {
+    fn merge(&self, defaults: &Self) -> Self {
+        Self {
+            data: merge_opt(&self.data, &defaults.data, PvcConfig::merge),
+            data_log: merge_opt(&self.data_log, &defaults.data_log, PvcConfig::merge),
+        }
+    }
+}
+
+impl PvcConfig {
+    fn merge(&self, defaults: &Self) -> Self {
+        Self {
+            capacity: self.capacity.clone().or_else(|| defaults.capacity.clone()),
+            storage_class_name: self
+                .storage_class_name
+                .clone()
+                .or_else(|| defaults.storage_class_name.clone()),
+            selectors: self
+                .selectors
+                .clone()
+                .or_else(|| defaults.selectors.clone()),
+        }
+    }
+}
+
+/// Merges two optional structs, falling back to whichever side is set if only one of them is
+pub(crate) fn merge_opt<T: Clone>(
+    value: &Option<T>,
+    defaults: &Option<T>,
+    merge: impl FnOnce(&T, &T) -> T,
+) -> Option<T> {
+    match (value, defaults) {
+        (Some(value), Some(defaults)) => Some(merge(value, defaults)),
+        (value, defaults) => value.clone().or_else(|| defaults.clone()),
+    }
+}
+
+/// Converts a [`Quantity`] (such as `512Mi`, `2G` or `1e9`) into bytes
+///
+/// This accepts the same syntax as Kubernetes: a signed decimal number, followed by a binary suffix (`Ki` to `Ei`), a
+/// decimal suffix (`n` to `E`), or a decimal exponent (`e` or `E` followed by a signed integer).
+pub fn quantity_to_bytes(quantity: &Quantity) -> Result<f64, QuantityError> {
+    let value = quantity.0.trim();
+    let split_at = value
+        .char_indices()
+        .find(|&(i, c)| !(c.is_ascii_digit() || c == '.' || (i == 0 && matches!(c, '+' | '-'))))
+        .map_or(value.len(), |(i, _)| i);
+    let (number, suffix) = value.split_at(split_at);
+    let multiplier: f64 = match suffix {
+        "Ki" => 2f64.powi(10),
+        "Mi" => 2f64.powi(20),
+        "Gi" => 2f64.powi(30),
+        "Ti" => 2f64.powi(40),
+        "Pi" => 2f64.powi(50),
+        "Ei" => 2f64.powi(60),
+        "n" => 1e-9,
+        "u" => 1e-6,
+        "m" => 1e-3,
+        "" => 1.0,
+        "k" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        "T" => 1e12,
+        "P" => 1e15,
+        "E" => 1e18,
+        _ if suffix.starts_with(['e', 'E']) => {
+            let exponent = suffix[1..]
+                .parse::<i32>()
+                .context(InvalidExponentSnafu { quantity: value })?;
+            10f64.powi(exponent)
+        }
+        _ => return UnknownSuffixSnafu { quantity: value }.fail(),
+    };
+    let number = number
+        .parse::<f64>()
+        .context(InvalidNumberSnafu { quantity: value })?;
+    Ok(number * multiplier)
+}
+
+/// Converts a memory [`Quantity`] (such as `512Mi` or `2G`) into mebibytes, rounding down
+pub fn quantity_to_mebibytes(quantity: &Quantity) -> Result<u64, QuantityError> {
+    Ok((quantity_to_bytes(quantity)? / (1024.0 * 1024.0)) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_quantities_to_bytes() {
+        for (quantity, bytes) in [
+            ("0", 0.0),
+            ("512", 512.0),
+            ("+512", 512.0),
+            ("-1k", -1e3),
+            ("1.5", 1.5),
+            ("1.", 1.0),
+            (".5k", 500.0),
+            ("100m", 0.1),
+            ("5u", 5e-6),
+            ("5n", 5e-9),
+            ("2k", 2e3),
+            ("2M", 2e6),
+            ("2G", 2e9),
+            ("2T", 2e12),
+            ("2P", 2e15),
+            ("2E", 2e18),
+            ("1Ki", 1024.0),
+            ("1Mi", 1048576.0),
+            ("1.5Gi", 1610612736.0),
+            ("1Ti", 1099511627776.0),
+            ("1Pi", 1125899906842624.0),
+            ("1Ei", 1152921504606846976.0),
+            ("1e9", 1e9),
+            ("1E9", 1e9),
+            ("1e+3", 1e3),
+            ("5e-3", 5e-3),
+            (" 1Gi ", 1073741824.0),
+        ] {
+            let converted = quantity_to_bytes(&Quantity(quantity.to_string()))
+                .unwrap_or_else(|err| panic!("failed to convert {:?}: {}", quantity, err));
+            assert!(
+                (converted - bytes).abs() <= bytes.abs() * 1e-12,
+                "{:?} was converted to {}, expected {}",
+                quantity,
+                converted,
+                bytes
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_quantities() {
+        for quantity in [
+            "", "Gi", ".", "1.2.3", "1+2", "1gi", "1Ki2", "1K", "1e", "1e1.5", "1ee3", "-+1",
+        ] {
+            assert!(
+                quantity_to_bytes(&Quantity(quantity.to_string())).is_err(),
+                "{:?} should be rejected",
+                quantity
+            );
+        }
+    }
+
+    #[test]
+    fn converts_quantities_to_mebibytes() {
+        for (quantity, mebibytes) in [
+            ("512Mi", 512),
+            ("1Gi", 1024),
+            ("1G", 953),
+            ("1e9", 953),
+            ("1.5Gi", 1536),
+            ("1048575", 0),
+        ] {
+            assert_eq!(
+                quantity_to_mebibytes(&Quantity(quantity.to_string())).unwrap(),
+                mebibytes,
+                "{:?}",
+                quantity
+            );
+        }
+    }
+}
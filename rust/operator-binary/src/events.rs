@@ -0,0 +1,87 @@
+//! Publishes Kubernetes `Event`s about notable reconciliation outcomes
+//!
+//! This lets users follow what the operator is doing to their objects (with `kubectl describe`) without needing access to
+//! the operator's logs. Events are informational, so failing to publish one is logged rather than failing the
+//! reconciliation.
+
+use stackable_operator::{
+    k8s_openapi::{
+        api::{core::v1::ObjectReference, events::v1::Event},
+        apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta},
+        chrono::Utc,
+    },
+    kube::{api::PostParams, runtime::reflector::ObjectRef, Resource, ResourceExt},
+};
+
+use crate::APP_NAME;
+
+const REPORTING_CONTROLLER: &str = "zookeeper.stackable.tech/operator";
+
+#[derive(Clone, Copy, Debug)]
+pub enum EventType {
+    Normal,
+    Warning,
+}
+
+/// Publishes an event about `obj`
+///
+/// `reason` is a short machine-readable description of what happened (such as `StatefulSetCreated`), `action` what the
+/// operator was doing at the time (such as `Reconcile`), and `note` a human-readable explanation.
+pub async fn publish_event<K>(
+    client: &stackable_operator::client::Client,
+    obj: &K,
+    type_: EventType,
+    reason: &str,
+    action: &str,
+    note: impl Into<String>,
+) where
+    K: Resource<DynamicType = ()>,
+{
+    let ns = match obj.namespace() {
+        Some(ns) => ns,
+        None => return,
+    };
+    let note = note.into();
+    let event = Event {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{}-", obj.name())),
+            namespace: Some(ns.clone()),
+            ..ObjectMeta::default()
+        },
+        event_time: MicroTime(Utc::now()),
+        type_: Some(format!("{:?}", type_)),
+        reason: Some(reason.to_string()),
+        action: Some(action.to_string()),
+        // Event notes are limited to 1kB
+        note: Some(note.chars().take(1024).collect()),
+        reporting_controller: Some(REPORTING_CONTROLLER.to_string()),
+        reporting_instance: Some(format!("{}-operator", APP_NAME)),
+        regarding: Some(ObjectReference {
+            api_version: Some(K::api_version(&()).into_owned()),
+            kind: Some(K::kind(&()).into_owned()),
+            name: Some(obj.name()),
+            namespace: Some(ns.clone()),
+            uid: obj.meta().uid.clone(),
+            resource_version: obj.meta().resource_version.clone(),
+            ..ObjectReference::default()
+        }),
+        related: None,
+        series: None,
+        deprecated_count: None,
+        deprecated_first_timestamp: None,
+        deprecated_last_timestamp: None,
+        deprecated_source: None,
+    };
+    if let Err(err) = client
+        .get_namespaced_api::<Event>(&ns)
+        .create(&PostParams::default(), &event)
+        .await
+    {
+        tracing::warn!(
+            object = %ObjectRef::<K>::new(&obj.name()).within(&ns),
+            reason,
+            error = &err as &dyn std::error::Error,
+            "Failed to publish event"
+        );
+    }
+}
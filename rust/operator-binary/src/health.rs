@@ -0,0 +1,344 @@
+//! Queries the health of each server of a [`ZookeeperCluster`]
+//!
+//! The servers are queried through the AdminServer, which serves the same commands as the four-letter words (such as
+//! `srvr`, or `monitor` for `mntr`) over HTTP. Unlike the four-letter words, these don't need to be whitelisted, and are still available when
+//! the client port only accepts TLS connections.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+use futures::future::join_all;
+use serde::Deserialize;
+use snafu::{ensure, ResultExt, Snafu};
+use stackable_zookeeper_crd::{ZookeeperCluster, ZookeeperPodRef, ZookeeperServerStatus};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::ADMIN_PORT;
+
+/// How long to wait for a server to respond, so that unreachable servers don't block the reconciliation
+const ADMIN_COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Snafu, Debug)]
+enum Error {
+    #[snafu(display("server did not respond in time"))]
+    Timeout { source: tokio::time::error::Elapsed },
+    #[snafu(display("failed to send {} command", command))]
+    Request {
+        source: std::io::Error,
+        command: String,
+    },
+    #[snafu(display("{} command failed: {}", command, status_line))]
+    HttpStatus {
+        command: String,
+        status_line: String,
+    },
+    #[snafu(display("{} command returned an invalid response", command))]
+    InvalidResponse {
+        source: serde_json::Error,
+        command: String,
+    },
+    #[snafu(display("{} command failed: {}", command, error))]
+    Command { command: String, error: String },
+}
+
+/// The response of the `srvr` command
+#[derive(Deserialize)]
+struct SrvrResponse {
+    error: Option<String>,
+    server_stats: Option<ServerStats>,
+}
+
+/// The response of the `monitor` command, the AdminServer's equivalent of `mntr`
+#[derive(Default, Deserialize)]
+struct MonitorResponse {
+    error: Option<String>,
+    avg_latency: Option<f64>,
+    max_latency: Option<u64>,
+    znode_count: Option<u64>,
+    /// Only reported by the leader
+    synced_followers: Option<u64>,
+    /// Only reported by the leader
+    pending_syncs: Option<u64>,
+}
+
+/// The response of the `voting_view` command
+#[derive(Deserialize)]
+struct VotingViewResponse {
+    error: Option<String>,
+    /// The voting members by their `myid`, the values describe their addresses
+    current_config: Option<BTreeMap<String, serde_json::Value>>,
+}
+
+#[derive(Default, Deserialize)]
+struct ServerStats {
+    server_state: Option<String>,
+    last_processed_zxid: Option<i64>,
+    outstanding_requests: Option<u64>,
+    num_alive_client_connections: Option<u64>,
+}
+
+/// Queries each server that is expected to be part of the ensemble
+///
+/// This never fails, servers that cannot be queried are reported with an `error` instead.
+pub async fn server_statuses(zk: &ZookeeperCluster) -> Vec<ZookeeperServerStatus> {
+    if zk.spec.stopped.unwrap_or(false) {
+        return Vec::new();
+    }
+    let pods = zk.pods().into_iter().flatten().collect::<Vec<_>>();
+    pod_statuses(&pods).await
+}
+
+/// Queries each of `pods`, see [`server_statuses`]
+pub async fn pod_statuses(pods: &[ZookeeperPodRef]) -> Vec<ZookeeperServerStatus> {
+    join_all(pods.iter().map(server_status)).await
+}
+
+/// The name of the `Pod` of the current leader, if any
+pub fn leader(servers: &[ZookeeperServerStatus]) -> Option<String> {
+    servers
+        .iter()
+        .find(|server| server.mode.as_deref() == Some("leader"))
+        .map(|server| server.pod_name.clone())
+}
+
+async fn server_status(pod: &ZookeeperPodRef) -> ZookeeperServerStatus {
+    let mut status = ZookeeperServerStatus {
+        pod_name: pod.pod_name.clone(),
+        myid: pod.zookeeper_myid,
+        ..ZookeeperServerStatus::default()
+    };
+    let host = pod.fqdn();
+    let (srvr, monitor) = futures::join!(srvr(&host), monitor(&host));
+    update_server_status(&mut status, srvr, monitor);
+    status
+}
+
+/// Fills in `status` from the responses of the `srvr` and `monitor` commands
+///
+/// The `error` describes the first command that failed, the other one is still reported if it succeeded.
+fn update_server_status(
+    status: &mut ZookeeperServerStatus,
+    srvr: Result<ServerStats, Error>,
+    monitor: Result<MonitorResponse, Error>,
+) {
+    match srvr {
+        Ok(stats) => {
+            status.mode = stats.server_state;
+            status.zxid = stats.last_processed_zxid.map(|zxid| format!("{:#x}", zxid));
+            status.outstanding_requests = stats.outstanding_requests;
+            status.connections = stats.num_alive_client_connections;
+        }
+        Err(err) => status.error = Some(err.to_string()),
+    }
+    match monitor {
+        Ok(monitor) => {
+            status.avg_latency = monitor.avg_latency;
+            status.max_latency = monitor.max_latency;
+            status.znode_count = monitor.znode_count;
+            status.synced_followers = monitor.synced_followers;
+            status.pending_syncs = monitor.pending_syncs;
+        }
+        Err(err) => {
+            status.error.get_or_insert_with(|| err.to_string());
+        }
+    }
+}
+
+/// The `myid`s of the voting members of the ensemble, according to the committed configuration of `pod`
+///
+/// Returns `None` if the server could not be queried.
+pub async fn voting_members(pod: &ZookeeperPodRef) -> Option<BTreeSet<u16>> {
+    match voting_view(&pod.fqdn()).await {
+        Ok(members) => Some(members),
+        Err(err) => {
+            tracing::warn!(
+                pod = pod.pod_name.as_str(),
+                error = &err as &dyn std::error::Error,
+                "Failed to query the voting members of the ensemble"
+            );
+            None
+        }
+    }
+}
+
+async fn voting_view(host: &str) -> Result<BTreeSet<u16>, Error> {
+    let command = "voting_view";
+    let body = tokio::time::timeout(ADMIN_COMMAND_TIMEOUT, admin_command(host, command))
+        .await
+        .context(TimeoutSnafu)??;
+    let response = serde_json::from_slice::<VotingViewResponse>(&body)
+        .with_context(|_| InvalidResponseSnafu { command })?;
+    if let Some(error) = response.error {
+        return CommandSnafu { command, error }.fail();
+    }
+    Ok(response
+        .current_config
+        .unwrap_or_default()
+        .keys()
+        .filter_map(|myid| myid.parse().ok())
+        .collect())
+}
+
+async fn srvr(host: &str) -> Result<ServerStats, Error> {
+    let command = "srvr";
+    let body = tokio::time::timeout(ADMIN_COMMAND_TIMEOUT, admin_command(host, command))
+        .await
+        .context(TimeoutSnafu)??;
+    let response = serde_json::from_slice::<SrvrResponse>(&body)
+        .with_context(|_| InvalidResponseSnafu { command })?;
+    if let Some(error) = response.error {
+        return CommandSnafu { command, error }.fail();
+    }
+    Ok(response.server_stats.unwrap_or_default())
+}
+
+async fn monitor(host: &str) -> Result<MonitorResponse, Error> {
+    let command = "monitor";
+    let body = tokio::time::timeout(ADMIN_COMMAND_TIMEOUT, admin_command(host, command))
+        .await
+        .context(TimeoutSnafu)??;
+    let response = serde_json::from_slice::<MonitorResponse>(&body)
+        .with_context(|_| InvalidResponseSnafu { command })?;
+    if let Some(error) = response.error {
+        return CommandSnafu { command, error }.fail();
+    }
+    Ok(response)
+}
+
+/// Runs an AdminServer command, and returns the body of its response
+///
+/// A plain HTTP/1.0 request is enough here, since the server closes the connection after responding.
+async fn admin_command(host: &str, command: &str) -> Result<Vec<u8>, Error> {
+    let mut stream = TcpStream::connect((host, ADMIN_PORT))
+        .await
+        .with_context(|_| RequestSnafu { command })?;
+    stream
+        .write_all(
+            format!(
+                "GET /commands/{} HTTP/1.0\r\nHost: {}\r\n\r\n",
+                command, host
+            )
+            .as_bytes(),
+        )
+        .await
+        .with_context(|_| RequestSnafu { command })?;
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .await
+        .with_context(|_| RequestSnafu { command })?;
+    let (head, body) = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(split) => (&response[..split], &response[split + 4..]),
+        None => (&response[..], &[][..]),
+    };
+    let status_line = String::from_utf8_lossy(head)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    ensure!(
+        status_line.split_whitespace().nth(1) == Some("200"),
+        HttpStatusSnafu {
+            command,
+            status_line
+        }
+    );
+    Ok(body.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse<T: serde::de::DeserializeOwned>(command: &str, body: &str) -> Result<T, Error> {
+        serde_json::from_str(body).with_context(|_| InvalidResponseSnafu { command })
+    }
+
+    #[test]
+    fn reports_srvr_and_monitor_fields() {
+        let srvr = parse::<SrvrResponse>(
+            "srvr",
+            r#"{
+                "server_stats": {
+                    "server_state": "leader",
+                    "last_processed_zxid": 4294967300,
+                    "outstanding_requests": 0,
+                    "num_alive_client_connections": 3
+                },
+                "command": "stats",
+                "error": null
+            }"#,
+        )
+        .unwrap();
+        let monitor = parse::<MonitorResponse>(
+            "monitor",
+            r#"{
+                "version": "3.8.0",
+                "avg_latency": 0.4,
+                "max_latency": 12,
+                "min_latency": 0,
+                "znode_count": 7,
+                "server_state": "leader",
+                "synced_followers": 1,
+                "pending_syncs": 1,
+                "command": "monitor",
+                "error": null
+            }"#,
+        );
+        let mut status = ZookeeperServerStatus::default();
+        update_server_status(&mut status, Ok(srvr.server_stats.unwrap()), monitor);
+        assert_eq!(
+            status,
+            ZookeeperServerStatus {
+                mode: Some("leader".to_string()),
+                zxid: Some("0x100000004".to_string()),
+                outstanding_requests: Some(0),
+                connections: Some(3),
+                avg_latency: Some(0.4),
+                max_latency: Some(12),
+                znode_count: Some(7),
+                synced_followers: Some(1),
+                pending_syncs: Some(1),
+                ..ZookeeperServerStatus::default()
+            }
+        );
+    }
+
+    #[test]
+    fn keeps_srvr_fields_if_monitor_fails() {
+        let mut status = ZookeeperServerStatus::default();
+        update_server_status(
+            &mut status,
+            Ok(ServerStats {
+                server_state: Some("follower".to_string()),
+                ..ServerStats::default()
+            }),
+            parse::<MonitorResponse>("monitor", "not json"),
+        );
+        assert_eq!(status.mode.as_deref(), Some("follower"));
+        assert_eq!(
+            status.error.as_deref(),
+            Some("monitor command returned an invalid response")
+        );
+
+        let mut status = ZookeeperServerStatus::default();
+        update_server_status(
+            &mut status,
+            CommandSnafu {
+                command: "srvr",
+                error: "This ZooKeeper instance is not currently serving requests",
+            }
+            .fail(),
+            parse::<MonitorResponse>("monitor", "{}"),
+        );
+        assert_eq!(
+            status.error.as_deref(),
+            Some("srvr command failed: This ZooKeeper instance is not currently serving requests")
+        );
+    }
+}
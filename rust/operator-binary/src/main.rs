@@ -1,16 +1,24 @@
 mod discovery;
-mod utils;
+mod events;
+mod health;
+mod membership;
+mod pod_overrides;
+mod restarts;
+mod shutdown;
+mod status;
+mod storage;
+mod super_user;
 mod zk_controller;
 mod znode_controller;
 
-use crate::utils::Tokio01ExecutorExt;
 use clap::Parser;
-use futures::{compat::Future01CompatExt, StreamExt};
+use futures::StreamExt;
 use stackable_operator::{
     cli::{Command, ProductOperatorRun},
     k8s_openapi::api::{
         apps::v1::StatefulSet,
-        core::v1::{ConfigMap, Endpoints, Service},
+        core::v1::{ConfigMap, Endpoints, Secret, Service},
+        policy::v1::PodDisruptionBudget,
     },
     kube::{
         api::{DynamicObject, ListParams},
@@ -30,6 +38,8 @@ mod built_info {
 
 pub const APP_NAME: &str = "zookeeper";
 pub const APP_PORT: u16 = 2181;
+pub const APP_SECURE_PORT: u16 = 2281;
+pub const ADMIN_PORT: u16 = 8080;
 
 #[derive(clap::Parser)]
 #[clap(about = built_info::PKG_DESCRIPTION, author = stackable_operator::cli::AUTHOR)]
@@ -52,8 +62,6 @@ fn erase_controller_result_type<K: Resource, E: std::error::Error + Send + Sync
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     stackable_operator::logging::initialize_logging("ZOOKEEPER_OPERATOR_LOG");
-    // tokio-zookeeper depends on Tokio 0.1
-    let tokio01_runtime = tokio01::runtime::Runtime::new()?;
 
     let opts = Opts::parse();
     match opts.cmd {
@@ -102,6 +110,12 @@ async fn main() -> anyhow::Result<()> {
                 )
                 .owns(client.get_all_api::<StatefulSet>(), ListParams::default())
                 .owns(client.get_all_api::<ConfigMap>(), ListParams::default())
+                // The users generated for ZookeeperZnodes, see zk_controller::znode_users_secret_name
+                .owns(client.get_all_api::<Secret>(), ListParams::default())
+                .owns(
+                    client.get_all_api::<PodDisruptionBudget>(),
+                    ListParams::default(),
+                )
                 .shutdown_on_signal()
                 .run(
                     zk_controller::reconcile_zk,
@@ -134,11 +148,7 @@ async fn main() -> anyhow::Result<()> {
                 )
                 .shutdown_on_signal()
                 .run(
-                    |znode, ctx| {
-                        tokio01_runtime
-                            .executor()
-                            .run_in_ctx(znode_controller::reconcile_znode(znode, ctx))
-                    },
+                    znode_controller::reconcile_znode,
                     znode_controller::error_policy,
                     Context::new(znode_controller::Ctx { client }),
                 );
@@ -161,6 +171,5 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
-    tokio01_runtime.shutdown_now().compat().await.unwrap();
     Ok(())
 }
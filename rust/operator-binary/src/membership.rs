@@ -0,0 +1,631 @@
+//! Changes the membership of the ensemble with ZooKeeper's dynamic reconfiguration when a [`ZookeeperCluster`] is scaled
+//!
+//! Rewriting the `server.N` lines of every server's `zoo.cfg` would restart every server, and could lose the quorum
+//! while the servers disagree about the membership. Instead, the membership is kept in a separate `zoo.cfg.dynamic`
+//! file that is only read when a server starts, and changed in the running ensemble with the `reconfig` command:
+//!
+//! - New servers are started first, with the current members and themselves in their `zoo.cfg.dynamic`, so that they
+//!   sync with the leader as non-voting followers. Once they are serving, they are added with `reconfig -add`.
+//! - Removed servers are removed with `reconfig -remove` first, and their [`StatefulSet`](`stackable_operator::k8s_openapi::api::apps::v1::StatefulSet`)
+//!   is only scaled down once the new membership has been committed.
+//!
+//! Changing the membership is restricted to ZooKeeper's super user, so the `reconfig` command is run by a [`Job`] that
+//! authenticates with the super user's credentials, see [`super_user`]. A failed [`Job`] is kept for
+//! [`RECONFIG_RETRY_DELAY`] before it is retried, so that its logs can be inspected, and so that a reconfiguration that
+//! keeps failing doesn't hammer the ensemble.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+use snafu::{OptionExt, ResultExt, Snafu};
+use stackable_operator::{
+    builder::{ContainerBuilder, ObjectMetaBuilder},
+    k8s_openapi::{
+        api::{
+            batch::v1::{Job, JobSpec},
+            core::v1::{ConfigMap, EnvVar, PodSpec, PodTemplateSpec},
+        },
+        chrono::{DateTime, Utc},
+    },
+    kube::{
+        self,
+        api::{DeleteParams, PostParams, PropagationPolicy},
+        runtime::reflector::ObjectRef,
+        ResourceExt,
+    },
+};
+use stackable_zookeeper_crd::{ZookeeperCluster, ZookeeperPodRef, ZookeeperServerStatus};
+
+use crate::{
+    events::{publish_event, EventType},
+    health, super_user,
+    zk_controller::{
+        tls_store_properties, tls_volume, zk_client_port, CLIENT_TLS_DIR, FIELD_MANAGER_SCOPE,
+    },
+    APP_PORT,
+};
+
+/// The key of the rolegroup `ConfigMap` that contains the membership, see [`dynamic_config`]
+pub const DYNAMIC_CONFIG_FILE: &str = "zoo.cfg.dynamic";
+/// How long a failed reconfiguration [`Job`] is kept before the reconfiguration is retried
+const RECONFIG_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("object has no namespace associated"))]
+    NoNamespace,
+    #[snafu(display("object defines no version"))]
+    ObjectHasNoVersion,
+    #[snafu(display("object is missing metadata to build owner reference"))]
+    ObjectMissingMetadataForOwnerRef {
+        source: stackable_operator::error::Error,
+    },
+    #[snafu(display("failed to find the servers of the ensemble"))]
+    FindServers {
+        source: stackable_zookeeper_crd::NoNamespaceError,
+    },
+    #[snafu(display("failed to find existing {}", cm))]
+    FindConfigMap {
+        source: stackable_operator::error::Error,
+        cm: ObjectRef<ConfigMap>,
+    },
+    #[snafu(display("failed to find existing {}", job))]
+    FindReconfigJob {
+        source: stackable_operator::error::Error,
+        job: ObjectRef<Job>,
+    },
+    #[snafu(display("failed to create {}", job))]
+    CreateReconfigJob {
+        source: kube::Error,
+        job: ObjectRef<Job>,
+    },
+    #[snafu(display("failed to delete finished {}", job))]
+    DeleteReconfigJob {
+        source: kube::Error,
+        job: ObjectRef<Job>,
+    },
+}
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The membership of the ensemble, and how to get it to the desired membership
+pub struct Membership {
+    /// The `myid`s of the servers that should be listed in `zoo.cfg.dynamic`
+    ///
+    /// This contains the committed members, and any new servers that are still waiting to be added.
+    pub members: BTreeSet<u16>,
+    /// The number of replicas that each rolegroup needs at least, by rolegroup name, so that members are only stopped
+    /// once they have been removed from the ensemble
+    pub min_replicas: BTreeMap<String, i32>,
+    /// Whether the committed membership still differs from the desired one
+    pub pending: bool,
+    /// Why the last reconfiguration failed, while it is waiting to be retried
+    pub failed_reconfiguration: Option<String>,
+}
+
+/// Determines the committed membership of the ensemble, and starts a reconfiguration if it differs from the desired one
+///
+/// The committed membership is queried from the current leader. If there is none (for example because the cluster is
+/// stopped or has lost its quorum), the membership from the existing `zoo.cfg.dynamic` is kept until it can be changed.
+pub async fn reconcile_membership(
+    client: &stackable_operator::client::Client,
+    zk: &ZookeeperCluster,
+    servers: &[ZookeeperServerStatus],
+) -> Result<Membership> {
+    let desired_pods = zk.pods().context(FindServersSnafu)?.collect::<Vec<_>>();
+    let desired = desired_pods
+        .iter()
+        .map(|pod| pod.zookeeper_myid)
+        .collect::<BTreeSet<_>>();
+    let leader = health::leader(servers)
+        .and_then(|leader| desired_pods.iter().find(|pod| pod.pod_name == leader));
+    let live_members = match leader {
+        Some(leader) => health::voting_members(leader).await,
+        None => None,
+    };
+    let committed = match &live_members {
+        Some(members) if !members.is_empty() => members.clone(),
+        _ => existing_members(client, zk)
+            .await?
+            .unwrap_or_else(|| desired.clone()),
+    };
+
+    let mut min_replicas = BTreeMap::<String, i32>::new();
+    for pod in committed
+        .iter()
+        .filter_map(|&myid| zk.server_pod_by_myid(myid))
+    {
+        let rolegroup_name = match zk
+            .spec
+            .servers
+            .iter()
+            .flat_map(|role| role.role_groups.keys())
+            .find(|name| {
+                zk.server_rolegroup_ref(*name).object_name() == pod.role_group_service_name
+            }) {
+            Some(name) => name.clone(),
+            None => continue,
+        };
+        let ordinal = pod
+            .pod_name
+            .rsplit('-')
+            .next()
+            .and_then(|ordinal| ordinal.parse::<i32>().ok())
+            .unwrap_or_default();
+        let replicas = min_replicas.entry(rolegroup_name).or_default();
+        *replicas = (*replicas).max(ordinal + 1);
+    }
+
+    let pending = committed != desired && !zk.spec.stopped.unwrap_or(false);
+    let mut failed_reconfiguration = None;
+    if pending && live_members.is_some() {
+        let remove = committed.difference(&desired).copied().collect::<Vec<_>>();
+        // New servers can only vote once they have synced with the leader
+        let add = desired
+            .difference(&committed)
+            .copied()
+            .filter(|myid| {
+                desired_pods
+                    .iter()
+                    .filter(|pod| pod.zookeeper_myid == *myid)
+                    .any(|pod| {
+                        servers.iter().any(|server| {
+                            server.pod_name == pod.pod_name
+                                && matches!(server.mode.as_deref(), Some("follower" | "observer"))
+                        })
+                    })
+            })
+            .collect::<Vec<_>>();
+        if !add.is_empty() || !remove.is_empty() {
+            failed_reconfiguration = run_reconfig_job(client, zk, &add, &remove).await?;
+        }
+    } else if !pending {
+        delete_reconfig_job(client, zk, None, false).await?;
+    }
+
+    Ok(Membership {
+        members: committed.union(&desired).copied().collect(),
+        min_replicas,
+        pending,
+        failed_reconfiguration,
+    })
+}
+
+/// The contents of the `zoo.cfg.dynamic` file that lists `members`
+pub fn dynamic_config(zk: &ZookeeperCluster, members: &BTreeSet<u16>) -> Vec<(String, String)> {
+    members
+        .iter()
+        .filter_map(|&myid| zk.server_pod_by_myid(myid))
+        .map(|pod| {
+            (
+                format!("server.{}", pod.zookeeper_myid),
+                server_address(zk, &pod),
+            )
+        })
+        .collect()
+}
+
+/// The address of a member, in the format of `zoo.cfg.dynamic` and `reconfig -add`
+fn server_address(zk: &ZookeeperCluster, pod: &ZookeeperPodRef) -> String {
+    let mut address = format!("{}:2888:3888:participant", pod.fqdn());
+    // The plaintext port would otherwise still be opened by the client address
+    if zk.client_tls().is_none() {
+        address.push_str(&format!(";{}", APP_PORT));
+    }
+    address
+}
+
+/// The members in the existing rolegroup `ConfigMap`s, if there are any
+///
+/// Clusters that were created before dynamic reconfiguration was used list their members in `zoo.cfg` instead.
+async fn existing_members(
+    client: &stackable_operator::client::Client,
+    zk: &ZookeeperCluster,
+) -> Result<Option<BTreeSet<u16>>> {
+    let ns = zk.namespace().context(NoNamespaceSnafu)?;
+    for rolegroup_name in zk
+        .spec
+        .servers
+        .iter()
+        .flat_map(|role| role.role_groups.keys())
+    {
+        let cm_name = zk.server_rolegroup_ref(rolegroup_name).object_name();
+        let cm = match client.get::<ConfigMap>(&cm_name, Some(&ns)).await {
+            Ok(cm) => cm,
+            Err(stackable_operator::error::Error::KubeError {
+                source: kube::Error::Api(kube::core::ErrorResponse { reason, .. }),
+            }) if reason == "NotFound" => continue,
+            Err(err) => {
+                return Err(err).with_context(|_| FindConfigMapSnafu {
+                    cm: ObjectRef::new(&cm_name).within(&ns),
+                })
+            }
+        };
+        let members = config_members(&cm.data.unwrap_or_default());
+        if !members.is_empty() {
+            return Ok(Some(members));
+        }
+    }
+    Ok(None)
+}
+
+/// The `myid`s of the `server.N` lines in the files of a rolegroup `ConfigMap`
+///
+/// `zoo.cfg.dynamic` is preferred, but `zoo.cfg` is also read since ZooKeeper moves its members to `zoo.cfg.dynamic`
+/// by itself.
+fn config_members(data: &BTreeMap<String, String>) -> BTreeSet<u16> {
+    [DYNAMIC_CONFIG_FILE, "zoo.cfg"]
+        .into_iter()
+        .filter_map(|file| data.get(file))
+        .flat_map(|config| config.lines())
+        .filter_map(|line| {
+            line.strip_prefix("server.")?
+                .split('=')
+                .next()?
+                .trim()
+                .parse::<u16>()
+                .ok()
+        })
+        .collect()
+}
+
+fn reconfig_job_name(zk: &ZookeeperCluster) -> String {
+    format!("{}-reconfig", zk.name())
+}
+
+/// Runs `reconfig` for the given changes, unless a reconfiguration is still running
+///
+/// Finished [`Job`]s are deleted rather than replaced right away, so that the next reconfiguration is based on the
+/// membership that was actually committed. Returns why the previous reconfiguration failed, while it is waiting to be
+/// retried.
+async fn run_reconfig_job(
+    client: &stackable_operator::client::Client,
+    zk: &ZookeeperCluster,
+    add: &[u16],
+    remove: &[u16],
+) -> Result<Option<String>> {
+    let ns = zk.namespace().context(NoNamespaceSnafu)?;
+    let name = reconfig_job_name(zk);
+    let job_ref = || ObjectRef::<Job>::new(&name).within(&ns);
+    match client.get::<Job>(&name, Some(&ns)).await {
+        Ok(job) => return delete_reconfig_job(client, zk, Some(job), true).await,
+        Err(stackable_operator::error::Error::KubeError {
+            source: kube::Error::Api(kube::core::ErrorResponse { reason, .. }),
+        }) if reason == "NotFound" => {}
+        Err(err) => return Err(err).with_context(|_| FindReconfigJobSnafu { job: job_ref() }),
+    }
+
+    let mut args = Vec::new();
+    if !add.is_empty() {
+        args.push(format!(
+            "-add {}",
+            add.iter()
+                .filter_map(|&myid| zk.server_pod_by_myid(myid))
+                .map(|pod| format!("server.{}={}", pod.zookeeper_myid, server_address(zk, &pod)))
+                .collect::<Vec<_>>()
+                .join(",")
+        ));
+    }
+    if !remove.is_empty() {
+        args.push(format!(
+            "-remove {}",
+            remove
+                .iter()
+                .map(u16::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        ));
+    }
+    let args = args.join(" ");
+    let server = format!(
+        "{}:{}",
+        zk.server_role_service_fqdn().context(NoNamespaceSnafu)?,
+        zk_client_port(zk)
+    );
+    // zkCli.sh does not fail when the command fails, so check that the new configuration was committed
+    let script = format!(
+        "printf 'addauth digest {}:%s\\nreconfig {}\\nquit\\n' \"$(cat {}/{})\" | bin/zkCli.sh -server {} 2>&1 | tee /tmp/reconfig.log && grep -q 'Committed new configuration' /tmp/reconfig.log",
+        super_user::SUPER_USER,
+        args,
+        super_user::CREDENTIALS_DIR,
+        super_user::PASSWORD_KEY,
+        server
+    );
+
+    let mut container = ContainerBuilder::new("reconfig");
+    container
+        .image(format!(
+            "docker.stackable.tech/stackable/zookeeper:{}-stackable0",
+            zk.spec
+                .version
+                .as_deref()
+                .context(ObjectHasNoVersionSnafu)?
+        ))
+        .command(vec!["sh".to_string(), "-c".to_string(), script])
+        .add_volume_mount(super_user::CREDENTIALS_VOLUME, super_user::CREDENTIALS_DIR);
+    let mut volumes = vec![super_user::credentials_volume(zk)];
+    if let Some(tls) = zk.client_tls() {
+        let client_jvm_flags = [
+            "-Dzookeeper.client.secure=true".to_string(),
+            "-Dzookeeper.clientCnxnSocket=org.apache.zookeeper.ClientCnxnSocketNetty".to_string(),
+        ]
+        .into_iter()
+        .chain(
+            tls_store_properties("zookeeper.ssl", CLIENT_TLS_DIR)
+                .into_iter()
+                .map(|(property, value)| format!("-D{}={}", property, value)),
+        )
+        .collect::<Vec<_>>();
+        container
+            .add_env_vars(vec![EnvVar {
+                name: "CLIENT_JVMFLAGS".to_string(),
+                value: Some(client_jvm_flags.join(" ")),
+                ..EnvVar::default()
+            }])
+            .add_volume_mount("client-tls", CLIENT_TLS_DIR);
+        volumes.push(tls_volume("client-tls", tls));
+    }
+
+    let job = Job {
+        metadata: ObjectMetaBuilder::new()
+            .name_and_namespace(zk)
+            .name(&name)
+            .ownerreference_from_resource(zk, None, Some(true))
+            .context(ObjectMissingMetadataForOwnerRefSnafu)?
+            .build(),
+        spec: Some(JobSpec {
+            backoff_limit: Some(0),
+            template: PodTemplateSpec {
+                metadata: None,
+                spec: Some(PodSpec {
+                    containers: vec![container.build()],
+                    restart_policy: Some("Never".to_string()),
+                    volumes: Some(volumes),
+                    ..PodSpec::default()
+                }),
+            },
+            ..JobSpec::default()
+        }),
+        status: None,
+    };
+    tracing::info!(job = %job_ref(), ?add, ?remove, "Changing the membership of the ensemble");
+    client
+        .get_namespaced_api::<Job>(&ns)
+        .create(&PostParams::default(), &job)
+        .await
+        .with_context(|_| CreateReconfigJobSnafu { job: job_ref() })?;
+    publish_event(
+        client,
+        zk,
+        EventType::Normal,
+        "Reconfiguring",
+        "Reconfigure",
+        format!(
+            "Changing the membership of the ensemble, adding servers {:?} and removing servers {:?}",
+            add, remove
+        ),
+    )
+    .await;
+    Ok(None)
+}
+
+/// The progress of a reconfiguration [`Job`]
+#[derive(Debug, PartialEq)]
+enum ReconfigJobState {
+    Running,
+    Succeeded,
+    /// The [`Job`] failed less than [`RECONFIG_RETRY_DELAY`] ago
+    RecentlyFailed,
+    Failed,
+}
+
+fn reconfig_job_state(job: &Job, now: DateTime<Utc>) -> ReconfigJobState {
+    let status = job.status.clone().unwrap_or_default();
+    if status.succeeded.unwrap_or(0) > 0 {
+        return ReconfigJobState::Succeeded;
+    }
+    if status.failed.unwrap_or(0) == 0 {
+        return ReconfigJobState::Running;
+    }
+    let failed_at = status
+        .conditions
+        .iter()
+        .flatten()
+        .find(|condition| condition.type_ == "Failed" && condition.status == "True")
+        .and_then(|condition| condition.last_transition_time.as_ref())
+        .or(status.start_time.as_ref());
+    match failed_at.and_then(|failed_at| (now - failed_at.0).to_std().ok()) {
+        Some(since) if since < RECONFIG_RETRY_DELAY => ReconfigJobState::RecentlyFailed,
+        _ => ReconfigJobState::Failed,
+    }
+}
+
+/// Deletes the reconfiguration [`Job`] once it has finished
+///
+/// The existing [`Job`] is looked up if it is not given. If `retrying`, failed [`Job`]s are only deleted (so that they
+/// are retried) once they have failed [`RECONFIG_RETRY_DELAY`] ago, and why they failed is returned until then.
+async fn delete_reconfig_job(
+    client: &stackable_operator::client::Client,
+    zk: &ZookeeperCluster,
+    job: Option<Job>,
+    retrying: bool,
+) -> Result<Option<String>> {
+    let ns = zk.namespace().context(NoNamespaceSnafu)?;
+    let name = reconfig_job_name(zk);
+    let job_ref = || ObjectRef::<Job>::new(&name).within(&ns);
+    let job = match job {
+        Some(job) => job,
+        None => match client.get::<Job>(&name, Some(&ns)).await {
+            Ok(job) => job,
+            Err(stackable_operator::error::Error::KubeError {
+                source: kube::Error::Api(kube::core::ErrorResponse { reason, .. }),
+            }) if reason == "NotFound" => return Ok(None),
+            Err(err) => return Err(err).with_context(|_| FindReconfigJobSnafu { job: job_ref() }),
+        },
+    };
+    let failure = format!(
+        "Failed to change the membership of the ensemble, see the logs of {} for details",
+        job_ref()
+    );
+    match reconfig_job_state(&job, Utc::now()) {
+        ReconfigJobState::Running => {
+            tracing::info!(job = %job_ref(), "Waiting for the reconfiguration to finish");
+            return Ok(None);
+        }
+        ReconfigJobState::RecentlyFailed if retrying => {
+            tracing::info!(job = %job_ref(), "Waiting to retry the failed reconfiguration");
+            return Ok(Some(failure));
+        }
+        ReconfigJobState::RecentlyFailed | ReconfigJobState::Failed => {
+            publish_event(
+                client,
+                zk,
+                EventType::Warning,
+                "ReconfigurationFailed",
+                "Reconfigure",
+                &failure,
+            )
+            .await;
+        }
+        ReconfigJobState::Succeeded => {}
+    }
+    client
+        .get_namespaced_api::<Job>(&ns)
+        .delete(
+            &name,
+            &DeleteParams {
+                propagation_policy: Some(PropagationPolicy::Background),
+                ..DeleteParams::default()
+            },
+        )
+        .await
+        .with_context(|_| DeleteReconfigJobSnafu { job: job_ref() })?;
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster() -> ZookeeperCluster {
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": "zookeeper.stackable.tech/v1alpha1",
+            "kind": "ZookeeperCluster",
+            "metadata": { "name": "zk", "namespace": "default" },
+            "spec": {
+                "version": "3.8.0",
+                "servers": { "roleGroups": {
+                    "a": { "replicas": 2 },
+                    "b": { "replicas": 1, "config": { "myidOffset": 10 } },
+                } },
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn lists_members_in_dynamic_config() {
+        assert_eq!(
+            dynamic_config(&cluster(), &[1, 2, 3, 10].into()),
+            [
+                (
+                    "server.1",
+                    "zk-server-a-0.zk-server-a.default.svc.cluster.local:2888:3888:participant;2181"
+                ),
+                (
+                    "server.2",
+                    "zk-server-a-1.zk-server-a.default.svc.cluster.local:2888:3888:participant;2181"
+                ),
+                (
+                    "server.3",
+                    "zk-server-a-2.zk-server-a.default.svc.cluster.local:2888:3888:participant;2181"
+                ),
+                (
+                    "server.10",
+                    "zk-server-b-0.zk-server-b.default.svc.cluster.local:2888:3888:participant;2181"
+                ),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+    }
+
+    #[test]
+    fn reads_members_from_config_files() {
+        let data = |files: &[(&str, &str)]| {
+            files
+                .iter()
+                .map(|(file, contents)| (file.to_string(), contents.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        assert_eq!(
+            config_members(&data(&[(
+                DYNAMIC_CONFIG_FILE,
+                "server.1=zk-server-a-0:2888:3888:participant;2181\nserver.10 = zk-server-b-0:2888:3888\n",
+            )])),
+            [1, 10].into()
+        );
+        // Clusters from before dynamic reconfiguration list their members in zoo.cfg
+        assert_eq!(
+            config_members(&data(&[(
+                "zoo.cfg",
+                "tickTime=2000\nserver.1=zk-server-a-0:2888:3888\nserver.2=zk-server-a-1:2888:3888\n",
+            )])),
+            [1, 2].into()
+        );
+        assert_eq!(
+            config_members(&data(&[(
+                "zoo.cfg",
+                "server.x=invalid\n#server.3=commented:2888:3888\nclientPort=2181\n"
+            )])),
+            BTreeSet::new()
+        );
+    }
+
+    fn job(status: serde_json::Value) -> Job {
+        serde_json::from_value(serde_json::json!({ "status": status })).unwrap()
+    }
+
+    #[test]
+    fn retries_failed_reconfig_jobs_after_delay() {
+        let now = "2022-03-01T12:00:00Z".parse().unwrap();
+        let state = |status| reconfig_job_state(&job(status), now);
+        assert_eq!(
+            state(serde_json::json!({ "active": 1, "startTime": "2022-03-01T11:00:00Z" })),
+            ReconfigJobState::Running
+        );
+        assert_eq!(
+            state(serde_json::json!({ "succeeded": 1 })),
+            ReconfigJobState::Succeeded
+        );
+        assert_eq!(
+            state(serde_json::json!({
+                "failed": 1,
+                "startTime": "2022-03-01T11:00:00Z",
+                "conditions": [{
+                    "type": "Failed",
+                    "status": "True",
+                    "lastTransitionTime": "2022-03-01T11:58:00Z",
+                }],
+            })),
+            ReconfigJobState::RecentlyFailed
+        );
+        assert_eq!(
+            state(serde_json::json!({
+                "failed": 1,
+                "startTime": "2022-03-01T11:00:00Z",
+                "conditions": [{
+                    "type": "Failed",
+                    "status": "True",
+                    "lastTransitionTime": "2022-03-01T11:50:00Z",
+                }],
+            })),
+            ReconfigJobState::Failed
+        );
+        // Falls back to when the Job was started
+        assert_eq!(
+            state(serde_json::json!({ "failed": 1, "startTime": "2022-03-01T11:59:00Z" })),
+            ReconfigJobState::RecentlyFailed
+        );
+    }
+}
@@ -0,0 +1,228 @@
+//! Merges user-provided `podOverrides` onto the pod templates generated by the operator
+//!
+//! This follows the rules of Kubernetes' strategic merge patches for the fields that are most commonly overridden:
+//! objects are merged recursively, lists of named objects (such as containers, volumes and environment variables) are
+//! merged by their merge key, and any other value (including other lists) is replaced.
+
+use serde_json::Value;
+use snafu::{ResultExt, Snafu};
+use stackable_operator::k8s_openapi::api::core::v1::PodTemplateSpec;
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("failed to serialize pod template"))]
+    SerializePodTemplate { source: serde_json::Error },
+    #[snafu(display("merged pod template is invalid"))]
+    DeserializePodTemplate { source: serde_json::Error },
+}
+
+/// Merges each of `overrides` (partial `PodTemplateSpec`s) onto `template`, in order
+pub fn apply_pod_overrides<'a>(
+    template: PodTemplateSpec,
+    overrides: impl IntoIterator<Item = &'a Value>,
+) -> Result<PodTemplateSpec, Error> {
+    let mut merged = serde_json::to_value(template).context(SerializePodTemplateSnafu)?;
+    for overrides in overrides {
+        merge(&mut merged, overrides.clone());
+    }
+    serde_json::from_value(merged).context(DeserializePodTemplateSnafu)
+}
+
+/// The key that identifies the items of a list field, if they should be merged rather than replaced
+fn list_merge_key(field: &str) -> Option<&'static str> {
+    match field {
+        "containers"
+        | "initContainers"
+        | "ephemeralContainers"
+        | "volumes"
+        | "env"
+        | "volumeMounts"
+        | "imagePullSecrets" => Some("name"),
+        "ports" => Some("containerPort"),
+        _ => None,
+    }
+}
+
+fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (field, value) in overrides {
+                match base.get_mut(&field) {
+                    Some(base_value) => merge_field(base_value, &field, value),
+                    None => {
+                        base.insert(field, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+fn merge_field(base: &mut Value, field: &str, overrides: Value) {
+    match (base, list_merge_key(field), overrides) {
+        (Value::Array(base_items), Some(key), Value::Array(items)) => {
+            merge_list(base_items, items, key)
+        }
+        (base, _, overrides) => merge(base, overrides),
+    }
+}
+
+fn merge_list(base: &mut Vec<Value>, overrides: Vec<Value>, key: &str) {
+    for item in overrides {
+        let existing = item.get(key).and_then(|id| {
+            base.iter()
+                .position(|base_item| base_item.get(key) == Some(id))
+        });
+        match existing {
+            Some(i) => merge(&mut base[i], item),
+            None => base.push(item),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use stackable_zookeeper_crd::ZookeeperCluster;
+
+    fn merged(mut base: Value, overrides: Value) -> Value {
+        merge(&mut base, overrides);
+        base
+    }
+
+    #[test]
+    fn merges_objects_recursively() {
+        assert_eq!(
+            merged(
+                json!({"metadata": {"labels": {"app": "zookeeper", "tier": "db"}}, "spec": {"hostNetwork": false}}),
+                json!({"metadata": {"labels": {"tier": "coordination", "team": "a"}}, "spec": {"hostNetwork": true}}),
+            ),
+            json!({
+                "metadata": {"labels": {"app": "zookeeper", "tier": "coordination", "team": "a"}},
+                "spec": {"hostNetwork": true},
+            })
+        );
+    }
+
+    #[test]
+    fn merges_named_lists_by_key() {
+        assert_eq!(
+            merged(
+                json!({"containers": [
+                    {
+                        "name": "zookeeper",
+                        "image": "zookeeper:3.8.0",
+                        "env": [{"name": "A", "value": "1"}, {"name": "B", "value": "2"}],
+                        "ports": [{"containerPort": 2181, "name": "zk"}],
+                    },
+                    {"name": "metrics", "image": "exporter"},
+                ]}),
+                json!({"containers": [
+                    {
+                        "name": "zookeeper",
+                        "env": [{"name": "B", "value": "3"}, {"name": "C", "value": "4"}],
+                        "ports": [{"containerPort": 2181, "hostPort": 2181}],
+                    },
+                    {"name": "sidecar", "image": "busybox"},
+                ]}),
+            ),
+            json!({"containers": [
+                {
+                    "name": "zookeeper",
+                    "image": "zookeeper:3.8.0",
+                    "env": [
+                        {"name": "A", "value": "1"},
+                        {"name": "B", "value": "3"},
+                        {"name": "C", "value": "4"},
+                    ],
+                    "ports": [{"containerPort": 2181, "name": "zk", "hostPort": 2181}],
+                },
+                {"name": "metrics", "image": "exporter"},
+                {"name": "sidecar", "image": "busybox"},
+            ]})
+        );
+    }
+
+    #[test]
+    fn replaces_other_lists() {
+        assert_eq!(
+            merged(
+                json!({"args": ["a", "b"], "tolerations": [{"key": "x"}]}),
+                json!({"args": ["c"], "tolerations": [{"key": "y"}]}),
+            ),
+            json!({"args": ["c"], "tolerations": [{"key": "y"}]})
+        );
+    }
+
+    #[test]
+    fn applies_overrides_in_order() {
+        let template: PodTemplateSpec = serde_json::from_value(json!({
+            "metadata": {"labels": {"app": "zookeeper"}},
+            "spec": {"containers": [{"name": "zookeeper", "image": "zookeeper"}]},
+        }))
+        .unwrap();
+        let role = json!({
+            "metadata": {"labels": {"level": "role"}},
+            "spec": {"containers": [{"name": "zookeeper", "imagePullPolicy": "Always"}]},
+        });
+        let rolegroup = json!({
+            "metadata": {"labels": {"level": "rolegroup"}},
+        });
+        let merged = apply_pod_overrides(template, [&role, &rolegroup]).unwrap();
+        assert_eq!(
+            serde_json::to_value(merged).unwrap(),
+            json!({
+                "metadata": {"labels": {"app": "zookeeper", "level": "rolegroup"}},
+                "spec": {"containers": [
+                    {"name": "zookeeper", "image": "zookeeper", "imagePullPolicy": "Always"},
+                ]},
+            })
+        );
+    }
+
+    #[test]
+    fn applies_partial_overrides_from_cluster() {
+        let zk: ZookeeperCluster = serde_yaml::from_str(
+            "
+            apiVersion: zookeeper.stackable.tech/v1alpha1
+            kind: ZookeeperCluster
+            metadata:
+              name: simple-zk
+            spec:
+              servers:
+                config:
+                  podOverrides:
+                    spec:
+                      tolerations:
+                        - key: dedicated
+                          value: zookeeper
+                          effect: NoSchedule
+                roleGroups:
+                  default:
+                    replicas: 3
+            ",
+        )
+        .unwrap();
+        let template: PodTemplateSpec = serde_json::from_value(json!({
+            "spec": {"containers": [{"name": "zookeeper", "image": "zookeeper"}]},
+        }))
+        .unwrap();
+        let merged = apply_pod_overrides(
+            template,
+            zk.spec
+                .servers
+                .iter()
+                .flat_map(|role| &role.config.config.pod_overrides),
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(merged.spec).unwrap(),
+            json!({
+                "containers": [{"name": "zookeeper", "image": "zookeeper"}],
+                "tolerations": [{"key": "dedicated", "value": "zookeeper", "effect": "NoSchedule"}],
+            })
+        );
+    }
+}
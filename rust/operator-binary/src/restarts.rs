@@ -0,0 +1,421 @@
+//! Restarts the outdated servers of a [`ZookeeperCluster`] one at a time, without losing the quorum
+//!
+//! The [`StatefulSet`]s use the `OnDelete` update strategy, so the `StatefulSet` controller never restarts `Pod`s by
+//! itself. Instead, the operator deletes outdated `Pod`s: the followers first, and the leader last (so that a new
+//! leader only needs to be elected once). The next server is only restarted once every server has rejoined the
+//! ensemble and caught up with the leader.
+//!
+//! Outdated servers that are not serving at all are restarted out of turn, since their updated configuration may be
+//! what fixes them. To keep a transient probe failure from taking down a server that still counts towards the quorum,
+//! this only happens once the server has been unready for [`FAILED_SERVER_GRACE_PERIOD`], or if enough other servers
+//! are serving that the ensemble keeps its quorum even if the failing server was still part of it.
+
+use std::time::Duration;
+
+use snafu::{OptionExt, ResultExt, Snafu};
+use stackable_operator::{
+    k8s_openapi::{
+        api::{apps::v1::StatefulSet, core::v1::Pod},
+        chrono::{DateTime, Utc},
+    },
+    kube::{
+        self,
+        api::{DeleteParams, ListParams},
+        runtime::reflector::ObjectRef,
+        ResourceExt,
+    },
+};
+use stackable_zookeeper_crd::{ZookeeperCluster, ZookeeperServerStatus};
+
+use crate::{
+    events::{publish_event, EventType},
+    storage::label_selector_string,
+};
+
+/// The label that the `StatefulSet` controller sets to the revision of each `Pod`'s template
+const REVISION_LABEL: &str = "controller-revision-hash";
+
+/// How long an outdated server must have been unready before it is restarted without waiting for the others
+const FAILED_SERVER_GRACE_PERIOD: Duration = Duration::from_secs(60);
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("object has no namespace associated"))]
+    NoNamespace,
+    #[snafu(display("failed to list Pods of {}", sts))]
+    ListPods {
+        source: kube::Error,
+        sts: ObjectRef<StatefulSet>,
+    },
+    #[snafu(display("failed to restart {}", pod))]
+    DeletePod {
+        source: kube::Error,
+        pod: ObjectRef<Pod>,
+    },
+}
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+struct ServerPod<'a> {
+    pod: Pod,
+    outdated: bool,
+    server: Option<&'a ZookeeperServerStatus>,
+}
+
+impl ServerPod<'_> {
+    fn is_leader(&self) -> bool {
+        self.server.and_then(|server| server.mode.as_deref()) == Some("leader")
+    }
+
+    /// Whether the server is serving as a member of the ensemble
+    fn is_serving(&self) -> bool {
+        let in_ensemble = matches!(
+            self.server.and_then(|server| server.mode.as_deref()),
+            Some("leader" | "follower" | "observer" | "standalone")
+        );
+        in_ensemble && is_pod_ready(&self.pod)
+    }
+
+    /// Whether the server has caught up with the leader's current epoch (the upper 32 bits of the zxid)
+    ///
+    /// Comparing the zxids themselves would race with the transactions that the ensemble keeps processing between
+    /// querying the leader and the follower. A server only serves once it has synced with the leader, after which it
+    /// is at the leader's epoch.
+    fn is_synced(&self, leader_epoch: Option<i64>) -> bool {
+        match (self.server.and_then(parse_zxid), leader_epoch) {
+            (Some(zxid), Some(leader_epoch)) => zxid_epoch(zxid) >= leader_epoch,
+            (_, None) => true,
+            (None, Some(_)) => false,
+        }
+    }
+
+    /// How long the `Pod` has been unready, as of `now`
+    ///
+    /// `Pod`s that have not been probed yet count as unready since their creation.
+    fn unready_for(&self, now: DateTime<Utc>) -> Option<Duration> {
+        let ready_condition = self
+            .pod
+            .status
+            .iter()
+            .flat_map(|status| status.conditions.iter().flatten())
+            .find(|condition| condition.type_ == "Ready");
+        let since = match ready_condition {
+            Some(condition) if condition.status == "True" => return None,
+            Some(condition) => condition.last_transition_time.as_ref(),
+            None => self.pod.metadata.creation_timestamp.as_ref(),
+        }?;
+        (now - since.0).to_std().ok()
+    }
+}
+
+/// Restarts at most one outdated server of `zk`, if that is safe
+///
+/// Returns the number of servers that are still outdated, which need further reconciliations to be restarted.
+pub async fn restart_outdated_servers(
+    client: &stackable_operator::client::Client,
+    zk: &ZookeeperCluster,
+    statefulsets: &[StatefulSet],
+    servers: &[ZookeeperServerStatus],
+) -> Result<usize> {
+    if zk.spec.stopped.unwrap_or(false) {
+        // The servers are being stopped anyway, see [`crate::shutdown`]
+        return Ok(0);
+    }
+    let ns = zk.namespace().context(NoNamespaceSnafu)?;
+    let pod_api = client.get_namespaced_api::<Pod>(&ns);
+    let mut pods = Vec::new();
+    for sts in statefulsets {
+        let update_revision = match sts
+            .status
+            .as_ref()
+            .and_then(|status| status.update_revision.as_deref())
+        {
+            Some(revision) => revision,
+            // The StatefulSet controller has not processed the StatefulSet yet
+            None => continue,
+        };
+        let selector = sts
+            .spec
+            .as_ref()
+            .map(|spec| spec.selector.clone())
+            .unwrap_or_default();
+        let sts_pods = pod_api
+            .list(&ListParams::default().labels(&label_selector_string(selector)))
+            .await
+            .with_context(|_| ListPodsSnafu {
+                sts: ObjectRef::from_obj(sts),
+            })?;
+        pods.extend(sts_pods.into_iter().map(|pod| ServerPod {
+            outdated: pod.labels().get(REVISION_LABEL).map(String::as_str) != Some(update_revision),
+            server: servers.iter().find(|server| server.pod_name == pod.name()),
+            pod,
+        }));
+    }
+    pods.sort_by_key(|pod| pod.pod.name());
+    let outdated = pods.iter().filter(|pod| pod.outdated).count();
+    if outdated == 0 {
+        return Ok(0);
+    }
+    if pods
+        .iter()
+        .any(|pod| pod.pod.metadata.deletion_timestamp.is_some())
+    {
+        tracing::info!("Waiting for the restarting server to shut down");
+        return Ok(outdated);
+    }
+
+    let restart = next_restart(&pods, servers, Utc::now());
+    if let Some(ServerPod { pod, .. }) = restart {
+        let pod_ref = ObjectRef::from_obj(pod);
+        tracing::info!(pod = %pod_ref, "Restarting outdated server");
+        pod_api
+            .delete(&pod.name(), &DeleteParams::default())
+            .await
+            .with_context(|_| DeletePodSnafu { pod: pod_ref })?;
+        publish_event(
+            client,
+            zk,
+            EventType::Normal,
+            "ServerRestarted",
+            "RestartServer",
+            format!(
+                "Restarted {} to apply its updated configuration",
+                pod.name()
+            ),
+        )
+        .await;
+    }
+    Ok(outdated)
+}
+
+/// The outdated server to restart next, if restarting any server is safe right now
+fn next_restart<'a, 'p>(
+    pods: &'p [ServerPod<'a>],
+    servers: &[ZookeeperServerStatus],
+    now: DateTime<Utc>,
+) -> Option<&'p ServerPod<'a>> {
+    let leader_epoch = pods
+        .iter()
+        .find(|pod| pod.is_leader())
+        .and_then(|pod| pod.server)
+        .and_then(parse_zxid)
+        .map(zxid_epoch);
+    let is_confirmed = |pod: &ServerPod| pod.is_serving() && pod.is_synced(leader_epoch);
+    let quorum = servers.len() / 2 + 1;
+    // Even if a failing server still counted towards the quorum, restarting it must leave a quorum behind
+    let keeps_quorum_without = |failing: &ServerPod| {
+        pods.iter()
+            .filter(|pod| pod.pod.name() != failing.pod.name() && is_confirmed(pod))
+            .count()
+            > quorum
+    };
+    let has_failed_for_grace_period = |pod: &ServerPod| {
+        pod.unready_for(now)
+            .map(|unready| unready >= FAILED_SERVER_GRACE_PERIOD)
+            .unwrap_or(false)
+    };
+    let failed = pods.iter().find(|pod| {
+        pod.outdated
+            && !pod.is_serving()
+            && (keeps_quorum_without(pod) || has_failed_for_grace_period(pod))
+    });
+    if let Some(failed) = failed {
+        // Restarting a server that is not serving anyway cannot hurt the quorum, and its update may be what fixes it
+        Some(failed)
+    } else if servers.iter().all(|server| {
+        pods.iter()
+            .any(|pod| pod.pod.name() == server.pod_name && is_confirmed(pod))
+    }) {
+        pods.iter()
+            .find(|pod| pod.outdated && !pod.is_leader())
+            .or_else(|| pods.iter().find(|pod| pod.outdated))
+    } else {
+        tracing::info!(
+            "Waiting for all servers to rejoin the ensemble before restarting the next one"
+        );
+        None
+    }
+}
+
+fn zxid_epoch(zxid: i64) -> i64 {
+    zxid >> 32
+}
+
+fn parse_zxid(server: &ZookeeperServerStatus) -> Option<i64> {
+    let zxid = server.zxid.as_deref()?;
+    i64::from_str_radix(zxid.trim_start_matches("0x"), 16).ok()
+}
+
+fn is_pod_ready(pod: &Pod) -> bool {
+    pod.status
+        .iter()
+        .flat_map(|status| status.conditions.iter().flatten())
+        .any(|condition| condition.type_ == "Ready" && condition.status == "True")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        "2022-03-01T12:00:00Z".parse().unwrap()
+    }
+
+    fn server(name: &str, mode: &str, zxid: &str) -> ZookeeperServerStatus {
+        ZookeeperServerStatus {
+            pod_name: name.to_string(),
+            mode: Some(mode.to_string()),
+            zxid: Some(zxid.to_string()),
+            ..ZookeeperServerStatus::default()
+        }
+    }
+
+    /// A `Pod` whose Ready condition last changed to `ready` at `since`
+    fn pod(name: &str, ready: bool, since: &str) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": { "name": name, "creationTimestamp": "2022-03-01T00:00:00Z" },
+            "status": { "conditions": [{
+                "type": "Ready",
+                "status": if ready { "True" } else { "False" },
+                "lastTransitionTime": since,
+            }] },
+        }))
+        .unwrap()
+    }
+
+    fn server_pods<'a>(
+        servers: &'a [ZookeeperServerStatus],
+        pods: Vec<(Pod, bool)>,
+    ) -> Vec<ServerPod<'a>> {
+        pods.into_iter()
+            .map(|(pod, outdated)| ServerPod {
+                server: servers.iter().find(|server| server.pod_name == pod.name()),
+                pod,
+                outdated,
+            })
+            .collect()
+    }
+
+    fn restarted(pods: &[ServerPod], servers: &[ZookeeperServerStatus]) -> Option<String> {
+        next_restart(pods, servers, now()).map(|pod| pod.pod.name())
+    }
+
+    #[test]
+    fn restarts_followers_before_leader() {
+        let servers = [
+            server("zk-0", "leader", "0x200000010"),
+            server("zk-1", "follower", "0x200000010"),
+            server("zk-2", "follower", "0x200000010"),
+        ];
+        let pods = server_pods(
+            &servers,
+            vec![
+                (pod("zk-0", true, "2022-03-01T00:00:00Z"), true),
+                (pod("zk-1", true, "2022-03-01T00:00:00Z"), false),
+                (pod("zk-2", true, "2022-03-01T00:00:00Z"), true),
+            ],
+        );
+        assert_eq!(restarted(&pods, &servers).as_deref(), Some("zk-2"));
+
+        let pods = server_pods(
+            &servers,
+            vec![
+                (pod("zk-0", true, "2022-03-01T00:00:00Z"), true),
+                (pod("zk-1", true, "2022-03-01T00:00:00Z"), false),
+                (pod("zk-2", true, "2022-03-01T00:00:00Z"), false),
+            ],
+        );
+        assert_eq!(restarted(&pods, &servers).as_deref(), Some("zk-0"));
+    }
+
+    #[test]
+    fn tolerates_followers_behind_within_the_leaders_epoch() {
+        let servers = [
+            server("zk-0", "leader", "0x200000010"),
+            server("zk-1", "follower", "0x200000004"),
+            server("zk-2", "follower", "0x200000010"),
+        ];
+        let pods = server_pods(
+            &servers,
+            vec![
+                (pod("zk-0", true, "2022-03-01T00:00:00Z"), false),
+                (pod("zk-1", true, "2022-03-01T00:00:00Z"), false),
+                (pod("zk-2", true, "2022-03-01T00:00:00Z"), true),
+            ],
+        );
+        assert_eq!(restarted(&pods, &servers).as_deref(), Some("zk-2"));
+    }
+
+    #[test]
+    fn waits_for_followers_of_an_older_epoch() {
+        let servers = [
+            server("zk-0", "leader", "0x200000010"),
+            server("zk-1", "follower", "0x100000030"),
+            server("zk-2", "follower", "0x200000010"),
+        ];
+        let pods = server_pods(
+            &servers,
+            vec![
+                (pod("zk-0", true, "2022-03-01T00:00:00Z"), false),
+                (pod("zk-1", true, "2022-03-01T00:00:00Z"), false),
+                (pod("zk-2", true, "2022-03-01T00:00:00Z"), true),
+            ],
+        );
+        assert_eq!(restarted(&pods, &servers), None);
+    }
+
+    #[test]
+    fn restarts_failing_servers_only_once_safe() {
+        let servers = [
+            server("zk-0", "leader", "0x200000010"),
+            server("zk-1", "follower", "0x200000010"),
+            server("zk-2", "follower", "0x200000010"),
+        ];
+        // Briefly unready, the server may still count towards the quorum
+        let pods = server_pods(
+            &servers,
+            vec![
+                (pod("zk-0", true, "2022-03-01T00:00:00Z"), false),
+                (pod("zk-1", false, "2022-03-01T11:59:30Z"), true),
+                (pod("zk-2", true, "2022-03-01T00:00:00Z"), true),
+            ],
+        );
+        assert_eq!(restarted(&pods, &servers), None);
+
+        // Unready for longer than the grace period
+        let pods = server_pods(
+            &servers,
+            vec![
+                (pod("zk-0", true, "2022-03-01T00:00:00Z"), false),
+                (pod("zk-1", false, "2022-03-01T11:59:00Z"), true),
+                (pod("zk-2", true, "2022-03-01T00:00:00Z"), true),
+            ],
+        );
+        assert_eq!(restarted(&pods, &servers).as_deref(), Some("zk-1"));
+    }
+
+    #[test]
+    fn restarts_failing_servers_right_away_if_quorum_is_kept() {
+        let servers = [
+            server("zk-0", "leader", "0x200000010"),
+            server("zk-1", "follower", "0x200000010"),
+            server("zk-2", "follower", "0x200000010"),
+            server("zk-3", "follower", "0x200000010"),
+            server("zk-4", "follower", "0x200000010"),
+        ];
+        let mut pods = vec![
+            (pod("zk-0", true, "2022-03-01T00:00:00Z"), false),
+            (pod("zk-1", false, "2022-03-01T11:59:50Z"), true),
+            (pod("zk-2", true, "2022-03-01T00:00:00Z"), false),
+            (pod("zk-3", true, "2022-03-01T00:00:00Z"), false),
+            (pod("zk-4", true, "2022-03-01T00:00:00Z"), false),
+        ];
+        assert_eq!(
+            restarted(&server_pods(&servers, pods.clone()), &servers).as_deref(),
+            Some("zk-1")
+        );
+
+        // Only a bare quorum would be left if zk-1 was still part of it
+        pods[4] = (pod("zk-4", false, "2022-03-01T11:59:50Z"), false);
+        assert_eq!(restarted(&server_pods(&servers, pods), &servers), None);
+    }
+}
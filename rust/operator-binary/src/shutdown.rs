@@ -0,0 +1,373 @@
+//! Stops the servers of a [`ZookeeperCluster`] one at a time when it is `stopped`
+//!
+//! Scaling every rolegroup to zero at once would stop the leader together with its followers. Instead, the servers
+//! are stopped one at a time, followers first and the leader last. [`StatefulSet`]s always remove the `Pod` with the
+//! highest ordinal when they are scaled down, so this is mostly done by choosing which rolegroup to scale down next.
+//!
+//! Once only the leader's rolegroup is left and the leader has its highest ordinal, the followers below it are stopped
+//! by deleting their `Pod`s instead. While the cluster is stopped, the pod templates select nodes with the
+//! [`STOPPED_NODE_SELECTOR`] label, which no node has, so the [`StatefulSet`] recreates these `Pod`s as unschedulable
+//! placeholders rather than restarting the servers. They are removed when the rolegroup is finally scaled down past
+//! them, after the leader.
+//!
+//! ZooKeeper offers no way to take a snapshot on demand (before 3.9), so no final snapshot is taken. Nothing is lost
+//! by this, since every committed transaction has already been synced to the transaction log, which the servers
+//! replay when they are started again.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use snafu::{OptionExt, ResultExt, Snafu};
+use stackable_operator::{
+    k8s_openapi::{
+        api::{apps::v1::StatefulSet, core::v1::Pod},
+        apimachinery::pkg::apis::meta::v1::LabelSelector,
+    },
+    kube::{
+        self,
+        api::{DeleteParams, ListParams},
+        runtime::reflector::ObjectRef,
+        ResourceExt,
+    },
+    labels::role_selector_labels,
+};
+use stackable_zookeeper_crd::{ZookeeperCluster, ZookeeperRole};
+
+use crate::{health, storage::label_selector_string, APP_NAME};
+
+/// The node label that the pod templates of a stopped cluster select, which keeps recreated `Pod`s from being scheduled
+pub const STOPPED_NODE_SELECTOR: &str = "zookeeper.stackable.tech/stopped";
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("object has no namespace associated"))]
+    NoNamespace,
+    #[snafu(display("failed to find existing {}", sts))]
+    FindStatefulSet {
+        source: stackable_operator::error::Error,
+        sts: ObjectRef<StatefulSet>,
+    },
+    #[snafu(display("failed to list servers"))]
+    ListPods { source: kube::Error },
+    #[snafu(display("failed to stop {}", pod))]
+    DeletePod {
+        source: kube::Error,
+        pod: ObjectRef<Pod>,
+    },
+}
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The progress of stopping a [`ZookeeperCluster`]
+pub struct Shutdown {
+    /// The number of replicas that each rolegroup's [`StatefulSet`] should keep for now, by rolegroup name
+    pub replicas: BTreeMap<String, i32>,
+    /// The number of servers that are still running or shutting down
+    pub running_servers: usize,
+}
+
+/// Decides which server of a `stopped` [`ZookeeperCluster`] to stop next, and stops it if that requires deleting its
+/// `Pod`
+///
+/// At most one server is stopped at a time, the next one is only stopped once the previous one has shut down.
+pub async fn plan_shutdown(
+    client: &stackable_operator::client::Client,
+    zk: &ZookeeperCluster,
+) -> Result<Shutdown> {
+    let ns = zk.namespace().context(NoNamespaceSnafu)?;
+    let mut replicas = BTreeMap::new();
+    for rolegroup_name in zk
+        .spec
+        .servers
+        .iter()
+        .flat_map(|role| role.role_groups.keys())
+    {
+        let sts_name = zk.server_rolegroup_ref(rolegroup_name).object_name();
+        let current_replicas = match client.get::<StatefulSet>(&sts_name, Some(&ns)).await {
+            Ok(sts) => sts.spec.and_then(|spec| spec.replicas).unwrap_or(1),
+            Err(stackable_operator::error::Error::KubeError {
+                source: kube::Error::Api(kube::core::ErrorResponse { reason, .. }),
+            }) if reason == "NotFound" => 0,
+            Err(err) => {
+                return Err(err).with_context(|_| FindStatefulSetSnafu {
+                    sts: ObjectRef::new(&sts_name).within(&ns),
+                })
+            }
+        };
+        replicas.insert(rolegroup_name.clone(), current_replicas);
+    }
+
+    let pod_api = client.get_namespaced_api::<Pod>(&ns);
+    let pods = pod_api
+        .list(
+            &ListParams::default().labels(&label_selector_string(LabelSelector {
+                match_labels: Some(role_selector_labels(
+                    zk,
+                    APP_NAME,
+                    &ZookeeperRole::Server.to_string(),
+                )),
+                ..LabelSelector::default()
+            })),
+        )
+        .await
+        .context(ListPodsSnafu)?;
+    let running_pod_names = pods
+        .iter()
+        .filter(|pod| !is_placeholder(pod))
+        .map(|pod| pod.name())
+        .collect::<BTreeSet<_>>();
+    let running_servers = running_pod_names.len();
+    if running_servers == 0 {
+        // Only placeholders are left, which can all be removed at once
+        replicas.values_mut().for_each(|count| *count = 0);
+        return Ok(Shutdown {
+            replicas,
+            running_servers,
+        });
+    }
+    // The StatefulSet controller may not have started to remove the previous server yet
+    let scaled_down_pod_exists = pods.iter().any(|pod| {
+        replicas.iter().any(|(rolegroup_name, &count)| {
+            let sts_name = zk.server_rolegroup_ref(rolegroup_name).object_name();
+            pod_ordinal(&sts_name, &pod.name()).map_or(false, |ordinal| ordinal >= count)
+        })
+    });
+    if scaled_down_pod_exists
+        || pods
+            .iter()
+            .any(|pod| pod.metadata.deletion_timestamp.is_some())
+    {
+        tracing::info!("Waiting for the stopping server to shut down");
+        return Ok(Shutdown {
+            replicas,
+            running_servers,
+        });
+    }
+
+    let running_pods = zk
+        .pods()
+        .into_iter()
+        .flatten()
+        .filter(|pod| running_pod_names.contains(&pod.pod_name))
+        .collect::<Vec<_>>();
+    let leader = health::leader(&health::pod_statuses(&running_pods).await);
+    match next_to_stop(zk, &replicas, &running_pod_names, leader.as_deref()) {
+        Some(StopStep::ScaleDown { rolegroup }) => {
+            if let Some(count) = replicas.get_mut(&rolegroup) {
+                tracing::info!(
+                    pod = %format!("{}-{}", zk.server_rolegroup_ref(&rolegroup).object_name(), *count - 1),
+                    "Stopping server"
+                );
+                *count -= 1;
+            }
+        }
+        Some(StopStep::DeletePod { pod }) => {
+            tracing::info!(pod = pod.as_str(), "Stopping server before the leader");
+            pod_api
+                .delete(&pod, &DeleteParams::default())
+                .await
+                .with_context(|_| DeletePodSnafu {
+                    pod: ObjectRef::new(&pod).within(&ns),
+                })?;
+        }
+        None => {}
+    }
+    Ok(Shutdown {
+        replicas,
+        running_servers,
+    })
+}
+
+/// Whether `pod` is an unschedulable placeholder for a server that has already been stopped
+fn is_placeholder(pod: &Pod) -> bool {
+    pod.spec
+        .as_ref()
+        .and_then(|spec| spec.node_selector.as_ref())
+        .map_or(false, |node_selector| {
+            node_selector.contains_key(STOPPED_NODE_SELECTOR)
+        })
+}
+
+/// The ordinal of `pod_name`, if it belongs to the [`StatefulSet`] `sts_name`
+fn pod_ordinal(sts_name: &str, pod_name: &str) -> Option<i32> {
+    pod_name
+        .strip_prefix(sts_name)?
+        .strip_prefix('-')?
+        .parse()
+        .ok()
+}
+
+/// How to stop the next server
+#[derive(Debug, PartialEq)]
+enum StopStep {
+    /// Scale down the rolegroup, which removes its `Pod` with the highest ordinal
+    ScaleDown { rolegroup: String },
+    /// Delete the `Pod`, which is then recreated as a placeholder
+    DeletePod { pod: String },
+}
+
+/// Chooses how to stop the next of the `running` servers
+///
+/// Placeholders are removed first, since that does not stop any server. Then rolegroups whose highest `Pod` is not
+/// the `leader` are scaled down. Once only the leader's rolegroup is left, its followers are deleted, and the rolegroup
+/// is only scaled down past the leader once it is the last running server.
+fn next_to_stop(
+    zk: &ZookeeperCluster,
+    replicas: &BTreeMap<String, i32>,
+    running: &BTreeSet<String>,
+    leader: Option<&str>,
+) -> Option<StopStep> {
+    let pod_name = |rolegroup_name: &str, ordinal: i32| {
+        format!(
+            "{}-{}",
+            zk.server_rolegroup_ref(rolegroup_name).object_name(),
+            ordinal
+        )
+    };
+    let last_pods = replicas
+        .iter()
+        .filter(|(_, count)| **count > 0)
+        .map(|(rolegroup_name, count)| (rolegroup_name, pod_name(rolegroup_name, count - 1)))
+        .collect::<Vec<_>>();
+    let scale_down = |(rolegroup_name, _): &(&String, String)| StopStep::ScaleDown {
+        rolegroup: rolegroup_name.to_string(),
+    };
+    if let Some(last_pod) = last_pods
+        .iter()
+        .find(|(_, pod_name)| !running.contains(pod_name))
+        .or_else(|| {
+            last_pods
+                .iter()
+                .find(|(_, pod_name)| Some(pod_name.as_str()) != leader)
+        })
+    {
+        return Some(scale_down(last_pod));
+    }
+    // The leader is the last Pod of every remaining rolegroup, so there is only one left
+    let leader_rolegroup = last_pods.first()?;
+    let count = replicas[leader_rolegroup.0];
+    (0..count - 1)
+        .rev()
+        .map(|ordinal| pod_name(leader_rolegroup.0, ordinal))
+        .find(|pod_name| running.contains(pod_name))
+        .map(|pod| StopStep::DeletePod { pod })
+        .or_else(|| Some(scale_down(leader_rolegroup)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster() -> ZookeeperCluster {
+        serde_json::from_value(serde_json::json!({
+            "apiVersion": "zookeeper.stackable.tech/v1alpha1",
+            "kind": "ZookeeperCluster",
+            "metadata": { "name": "zk", "namespace": "default" },
+            "spec": { "servers": { "roleGroups": {
+                "a": { "replicas": 3 },
+                "b": { "replicas": 2 },
+            } } },
+        }))
+        .unwrap()
+    }
+
+    fn replicas(a: i32, b: i32) -> BTreeMap<String, i32> {
+        [("a".to_string(), a), ("b".to_string(), b)].into()
+    }
+
+    /// All `Pod`s that the `replicas` would keep, except for the `placeholders`
+    fn running(replicas: &BTreeMap<String, i32>, placeholders: &[&str]) -> BTreeSet<String> {
+        replicas
+            .iter()
+            .flat_map(|(rolegroup_name, &count)| {
+                (0..count).map(move |ordinal| format!("zk-server-{}-{}", rolegroup_name, ordinal))
+            })
+            .filter(|pod_name| !placeholders.contains(&pod_name.as_str()))
+            .collect()
+    }
+
+    fn next_step(
+        replicas: &BTreeMap<String, i32>,
+        placeholders: &[&str],
+        leader: Option<&str>,
+    ) -> Option<StopStep> {
+        next_to_stop(
+            &cluster(),
+            replicas,
+            &running(replicas, placeholders),
+            leader,
+        )
+    }
+
+    fn scale_down(rolegroup: &str) -> Option<StopStep> {
+        Some(StopStep::ScaleDown {
+            rolegroup: rolegroup.to_string(),
+        })
+    }
+
+    fn delete_pod(pod: &str) -> Option<StopStep> {
+        Some(StopStep::DeletePod {
+            pod: pod.to_string(),
+        })
+    }
+
+    #[test]
+    fn stops_the_highest_ordinal_first() {
+        assert_eq!(next_step(&replicas(3, 2), &[], None), scale_down("a"));
+        assert_eq!(
+            next_step(&replicas(0, 2), &[], Some("zk-server-a-0")),
+            scale_down("b")
+        );
+    }
+
+    #[test]
+    fn stops_other_rolegroups_before_the_leader() {
+        assert_eq!(
+            next_step(&replicas(3, 2), &[], Some("zk-server-a-2")),
+            scale_down("b")
+        );
+        assert_eq!(
+            next_step(&replicas(1, 1), &[], Some("zk-server-a-0")),
+            scale_down("b")
+        );
+    }
+
+    #[test]
+    fn stops_the_followers_below_the_leader_first() {
+        let leader = Some("zk-server-a-2");
+        assert_eq!(
+            next_step(&replicas(3, 0), &[], leader),
+            delete_pod("zk-server-a-1")
+        );
+        assert_eq!(
+            next_step(&replicas(3, 0), &["zk-server-a-1"], leader),
+            delete_pod("zk-server-a-0")
+        );
+        assert_eq!(
+            next_step(&replicas(3, 0), &["zk-server-a-0", "zk-server-a-1"], leader),
+            scale_down("a")
+        );
+    }
+
+    #[test]
+    fn removes_placeholders_before_stopping_servers() {
+        assert_eq!(
+            next_step(&replicas(2, 2), &["zk-server-b-1"], None),
+            scale_down("b")
+        );
+        assert_eq!(
+            next_step(&replicas(2, 0), &["zk-server-a-0", "zk-server-a-1"], None),
+            scale_down("a")
+        );
+    }
+
+    #[test]
+    fn stops_nothing_once_all_rolegroups_are_stopped() {
+        assert_eq!(next_step(&replicas(0, 0), &[], None), None);
+    }
+
+    #[test]
+    fn finds_pod_ordinals() {
+        assert_eq!(pod_ordinal("zk-server-a", "zk-server-a-12"), Some(12));
+        assert_eq!(pod_ordinal("zk-server-a", "zk-server-ab-0"), None);
+        assert_eq!(pod_ordinal("zk-server-a", "zk-server-b-0"), None);
+    }
+}
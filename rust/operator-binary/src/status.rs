@@ -0,0 +1,60 @@
+//! Maintains the standard `conditions` in the statuses of our custom resources
+
+use stackable_operator::k8s_openapi::{
+    apimachinery::pkg::apis::meta::v1::{Condition, Time},
+    chrono::Utc,
+};
+
+pub const AVAILABLE: &str = "Available";
+pub const PROGRESSING: &str = "Progressing";
+pub const DEGRADED: &str = "Degraded";
+pub const RECONCILIATION_PAUSED: &str = "ReconciliationPaused";
+pub const READY: &str = "Ready";
+
+/// Builds a condition that transitioned just now, see [`set_condition`]
+pub fn condition(
+    type_: &str,
+    status: bool,
+    reason: &str,
+    message: impl Into<String>,
+    observed_generation: Option<i64>,
+) -> Condition {
+    Condition {
+        type_: type_.to_string(),
+        status: if status { "True" } else { "False" }.to_string(),
+        reason: reason.to_string(),
+        message: message.into(),
+        observed_generation,
+        last_transition_time: Time(Utc::now()),
+    }
+}
+
+/// Replaces the condition of the same type in `conditions` (or adds it if there is none)
+///
+/// The `lastTransitionTime` of the existing condition is kept if its status has not changed.
+pub fn set_condition(conditions: &mut Vec<Condition>, mut condition: Condition) {
+    match conditions
+        .iter_mut()
+        .find(|existing| existing.type_ == condition.type_)
+    {
+        Some(existing) => {
+            if existing.status == condition.status {
+                condition.last_transition_time = existing.last_transition_time.clone();
+            }
+            *existing = condition;
+        }
+        None => conditions.push(condition),
+    }
+}
+
+/// Renders an error including all of its causes, since the top-level message alone rarely explains what went wrong
+pub fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        source = err.source();
+    }
+    message
+}
@@ -0,0 +1,542 @@
+//! Expands the volumes of a rolegroup when its requested storage capacity is increased
+//!
+//! The `volumeClaimTemplates` of a [`StatefulSet`] are immutable, and are only used when creating new
+//! [`PersistentVolumeClaim`]s anyway. So the existing claims are expanded directly, and the [`StatefulSet`] is
+//! then recreated with the new templates. It is deleted with orphan propagation, so that its `Pod`s keep running
+//! and are adopted by the new [`StatefulSet`].
+//!
+//! Only the capacity can be changed this way. Changing the `storageClassName` or `selectors` of an existing volume
+//! would require its data to be migrated to a new volume, so such changes are rejected instead.
+
+use std::collections::BTreeMap;
+
+use snafu::{OptionExt, ResultExt, Snafu};
+use stackable_operator::{
+    k8s_openapi::{
+        api::{
+            apps::v1::StatefulSet,
+            core::v1::{PersistentVolumeClaim, PersistentVolumeClaimSpec, ResourceRequirements},
+            storage::v1::StorageClass,
+        },
+        apimachinery::pkg::{api::resource::Quantity, apis::meta::v1::LabelSelector},
+    },
+    kube::{
+        self,
+        api::{DeleteParams, ListParams, Patch, PatchParams, PropagationPolicy},
+        runtime::reflector::ObjectRef,
+        ResourceExt,
+    },
+};
+use stackable_zookeeper_crd::{
+    resources::{quantity_to_bytes, QuantityError},
+    VolumeResizeState, VolumeResizeStatus,
+};
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("object has no namespace associated"))]
+    NoNamespace,
+    #[snafu(display("failed to find existing {}", sts))]
+    FindStatefulSet {
+        source: stackable_operator::error::Error,
+        sts: ObjectRef<StatefulSet>,
+    },
+    #[snafu(display("failed to delete outdated {}", sts))]
+    DeleteStatefulSet {
+        source: kube::Error,
+        sts: ObjectRef<StatefulSet>,
+    },
+    #[snafu(display("failed to list PersistentVolumeClaims of {}", sts))]
+    ListPvcs {
+        source: kube::Error,
+        sts: ObjectRef<StatefulSet>,
+    },
+    #[snafu(display("failed to find StorageClass {}", storage_class))]
+    FindStorageClass {
+        source: stackable_operator::error::Error,
+        storage_class: String,
+    },
+    #[snafu(display("failed to expand {}", pvc))]
+    ExpandPvc {
+        source: kube::Error,
+        pvc: ObjectRef<PersistentVolumeClaim>,
+    },
+    #[snafu(display("{} has an invalid capacity", pvc))]
+    InvalidCapacity {
+        source: QuantityError,
+        pvc: ObjectRef<PersistentVolumeClaim>,
+    },
+    #[snafu(display("volume template {} has an invalid capacity", template))]
+    InvalidTemplateCapacity {
+        source: QuantityError,
+        template: String,
+    },
+    #[snafu(display(
+        "the {} of volume {} cannot be changed, since its data would not be migrated",
+        field,
+        template
+    ))]
+    ImmutableVolumeChange {
+        template: String,
+        field: &'static str,
+    },
+}
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The outcome of preparing a rolegroup's volumes for its desired [`StatefulSet`]
+pub struct VolumeReconciliation {
+    /// Whether the desired [`StatefulSet`] may be applied now
+    ///
+    /// This is `false` while the outdated [`StatefulSet`] is still being deleted.
+    pub apply_statefulset: bool,
+    /// Whether a [`StatefulSet`] for the rolegroup existed before this reconciliation
+    pub statefulset_existed: bool,
+    /// Whether the existing [`StatefulSet`] was deleted by this reconciliation, so that it can be recreated
+    pub recreating_statefulset: bool,
+    pub resizes: Vec<VolumeResizeStatus>,
+}
+
+/// Expands the existing volumes of `desired`'s rolegroup to the requested capacity, and deletes the existing
+/// [`StatefulSet`] if its `volumeClaimTemplates` are outdated
+pub async fn reconcile_rolegroup_volumes(
+    client: &stackable_operator::client::Client,
+    desired: &StatefulSet,
+) -> Result<VolumeReconciliation> {
+    let name = desired.name();
+    let ns = desired.namespace().context(NoNamespaceSnafu)?;
+    let sts_ref = || ObjectRef::<StatefulSet>::new(&name).within(&ns);
+    let existing = match client.get::<StatefulSet>(&name, Some(&ns)).await {
+        Ok(existing) => existing,
+        Err(stackable_operator::error::Error::KubeError {
+            source: kube::Error::Api(kube::core::ErrorResponse { reason, .. }),
+        }) if reason == "NotFound" => {
+            return Ok(VolumeReconciliation {
+                apply_statefulset: true,
+                statefulset_existed: false,
+                recreating_statefulset: false,
+                resizes: Vec::new(),
+            })
+        }
+        Err(err) => return Err(err).with_context(|_| FindStatefulSetSnafu { sts: sts_ref() }),
+    };
+    if existing.metadata.deletion_timestamp.is_some() {
+        tracing::info!(sts = %sts_ref(), "Waiting for outdated StatefulSet to be deleted");
+        return Ok(VolumeReconciliation {
+            apply_statefulset: false,
+            statefulset_existed: true,
+            recreating_statefulset: false,
+            resizes: Vec::new(),
+        });
+    }
+
+    let desired_templates = pvc_templates(desired);
+    let recreating_statefulset = templates_changed(&pvc_templates(&existing), &desired_templates)?;
+    let pvc_api = client.get_namespaced_api::<PersistentVolumeClaim>(&ns);
+    let pvcs = pvc_api
+        .list(
+            &ListParams::default().labels(&label_selector_string(
+                desired
+                    .spec
+                    .as_ref()
+                    .map(|spec| &spec.selector)
+                    .cloned()
+                    .unwrap_or_default(),
+            )),
+        )
+        .await
+        .with_context(|_| ListPvcsSnafu { sts: sts_ref() })?;
+    let mut resizes = Vec::new();
+    for pvc in pvcs {
+        let pvc_name = pvc.name();
+        let pvc_ref = || ObjectRef::<PersistentVolumeClaim>::new(&pvc_name).within(&ns);
+        let desired_capacity = match claim_template(&desired_templates, &name, &pvc_name)
+            .and_then(|template| template.capacity.clone())
+        {
+            Some(capacity) => capacity,
+            None => continue,
+        };
+        let requested_capacity = pvc_storage(pvc.spec.as_ref().and_then(|spec| {
+            spec.resources
+                .as_ref()
+                .and_then(|resources| resources.requests.as_ref())
+        }));
+        let current_capacity = pvc_storage(
+            pvc.status
+                .as_ref()
+                .and_then(|status| status.capacity.as_ref()),
+        );
+        let bytes = |quantity: &Quantity| {
+            quantity_to_bytes(quantity).with_context(|_| InvalidCapacitySnafu { pvc: pvc_ref() })
+        };
+
+        let needs_expansion = match &requested_capacity {
+            Some(requested) => bytes(&desired_capacity)? > bytes(requested)?,
+            None => false,
+        };
+        if needs_expansion {
+            if storage_class_allows_expansion(client, &pvc).await? {
+                tracing::info!(
+                    pvc = %pvc_ref(),
+                    capacity = %desired_capacity.0,
+                    "Expanding volume"
+                );
+                pvc_api
+                    .patch(
+                        &pvc_name,
+                        &PatchParams::default(),
+                        &Patch::Merge(PersistentVolumeClaim {
+                            spec: Some(PersistentVolumeClaimSpec {
+                                resources: Some(ResourceRequirements {
+                                    requests: Some(
+                                        [("storage".to_string(), desired_capacity.clone())].into(),
+                                    ),
+                                    ..Default::default()
+                                }),
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        }),
+                    )
+                    .await
+                    .with_context(|_| ExpandPvcSnafu { pvc: pvc_ref() })?;
+                resizes.push(VolumeResizeStatus {
+                    name: pvc_name.clone(),
+                    requested_capacity: desired_capacity,
+                    current_capacity,
+                    state: VolumeResizeState::Resizing,
+                });
+            } else {
+                tracing::warn!(
+                    pvc = %pvc_ref(),
+                    "Volume cannot be expanded, since its StorageClass does not allow volume expansion"
+                );
+                resizes.push(VolumeResizeStatus {
+                    name: pvc_name.clone(),
+                    requested_capacity: desired_capacity,
+                    current_capacity,
+                    state: VolumeResizeState::ExpansionNotSupported,
+                });
+            }
+        } else if let Some(requested) = requested_capacity {
+            // The expansion was requested by an earlier reconciliation, but the volume has not caught up yet
+            let resized = match &current_capacity {
+                Some(current) => bytes(current)? >= bytes(&requested)?,
+                None => false,
+            };
+            if !resized {
+                resizes.push(VolumeResizeStatus {
+                    name: pvc_name.clone(),
+                    requested_capacity: requested,
+                    current_capacity,
+                    state: VolumeResizeState::Resizing,
+                });
+            }
+        }
+    }
+
+    if recreating_statefulset {
+        tracing::info!(
+            sts = %sts_ref(),
+            "Recreating StatefulSet, since its volumeClaimTemplates have changed"
+        );
+        client
+            .get_namespaced_api::<StatefulSet>(&ns)
+            .delete(
+                &name,
+                &DeleteParams {
+                    propagation_policy: Some(PropagationPolicy::Orphan),
+                    ..DeleteParams::default()
+                },
+            )
+            .await
+            .with_context(|_| DeleteStatefulSetSnafu { sts: sts_ref() })?;
+    }
+    Ok(VolumeReconciliation {
+        apply_statefulset: !recreating_statefulset,
+        statefulset_existed: true,
+        recreating_statefulset,
+        resizes,
+    })
+}
+
+/// The parts of a `volumeClaimTemplate` that the operator manages
+#[derive(Debug, PartialEq)]
+struct PvcTemplate {
+    name: String,
+    capacity: Option<Quantity>,
+    storage_class_name: Option<String>,
+    selector: Option<LabelSelector>,
+}
+
+fn pvc_templates(sts: &StatefulSet) -> Vec<PvcTemplate> {
+    sts.spec
+        .iter()
+        .flat_map(|spec| spec.volume_claim_templates.iter().flatten())
+        .map(|template| {
+            let spec = template.spec.as_ref();
+            PvcTemplate {
+                name: template.name(),
+                capacity: pvc_storage(spec.and_then(|spec| {
+                    spec.resources
+                        .as_ref()
+                        .and_then(|resources| resources.requests.as_ref())
+                })),
+                storage_class_name: spec.and_then(|spec| spec.storage_class_name.clone()),
+                selector: spec.and_then(|spec| spec.selector.clone()),
+            }
+        })
+        .collect()
+}
+
+/// Whether the `existing` templates differ from the `desired` ones, so that the [`StatefulSet`] must be recreated
+///
+/// Capacities are compared by their size, since the API server may canonicalize them (such as `1024Mi` to `1Gi`).
+/// Fails if the storage class or selector of an existing template has changed.
+fn templates_changed(existing: &[PvcTemplate], desired: &[PvcTemplate]) -> Result<bool> {
+    let names = |templates: &[PvcTemplate]| {
+        templates
+            .iter()
+            .map(|template| template.name.clone())
+            .collect::<Vec<_>>()
+    };
+    if names(existing) != names(desired) {
+        return Ok(true);
+    }
+    let mut changed = false;
+    for (existing, desired) in existing.iter().zip(desired) {
+        if existing.storage_class_name != desired.storage_class_name {
+            return ImmutableVolumeChangeSnafu {
+                template: &desired.name,
+                field: "storageClassName",
+            }
+            .fail();
+        }
+        if existing.selector != desired.selector {
+            return ImmutableVolumeChangeSnafu {
+                template: &desired.name,
+                field: "selectors",
+            }
+            .fail();
+        }
+        let bytes = |template: &PvcTemplate| {
+            template
+                .capacity
+                .as_ref()
+                .map(quantity_to_bytes)
+                .transpose()
+                .with_context(|_| InvalidTemplateCapacitySnafu {
+                    template: &template.name,
+                })
+        };
+        changed |= bytes(existing)? != bytes(desired)?;
+    }
+    Ok(changed)
+}
+
+/// Finds the template that the claim `pvc_name` of the [`StatefulSet`] `sts_name` was created from
+///
+/// StatefulSets name their claims `<template>-<statefulset>-<ordinal>`.
+fn claim_template<'a>(
+    templates: &'a [PvcTemplate],
+    sts_name: &str,
+    pvc_name: &str,
+) -> Option<&'a PvcTemplate> {
+    templates.iter().find(|template| {
+        pvc_name
+            .strip_prefix(&format!("{}-{}-", template.name, sts_name))
+            .map_or(false, |ordinal| {
+                !ordinal.is_empty() && ordinal.chars().all(|c| c.is_ascii_digit())
+            })
+    })
+}
+
+fn pvc_storage(quantities: Option<&BTreeMap<String, Quantity>>) -> Option<Quantity> {
+    quantities.and_then(|quantities| quantities.get("storage").cloned())
+}
+
+async fn storage_class_allows_expansion(
+    client: &stackable_operator::client::Client,
+    pvc: &PersistentVolumeClaim,
+) -> Result<bool> {
+    let storage_class_name = match pvc
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.storage_class_name.as_deref())
+    {
+        Some(name) if !name.is_empty() => name,
+        // Statically provisioned volumes cannot be expanded
+        _ => return Ok(false),
+    };
+    let storage_class = client
+        .get::<StorageClass>(storage_class_name, None)
+        .await
+        .with_context(|_| FindStorageClassSnafu {
+            storage_class: storage_class_name,
+        })?;
+    Ok(storage_class.allow_volume_expansion.unwrap_or(false))
+}
+
+/// Renders the `matchLabels` of a [`LabelSelector`] into the format expected by [`ListParams::labels`]
+pub fn label_selector_string(selector: LabelSelector) -> String {
+    selector
+        .match_labels
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statefulset() -> StatefulSet {
+        serde_yaml::from_str(
+            "
+            apiVersion: apps/v1
+            kind: StatefulSet
+            metadata:
+              name: zk-server-default
+            spec:
+              serviceName: zk-server-default
+              selector:
+                matchLabels:
+                  app.kubernetes.io/instance: zk
+              template: {}
+              volumeClaimTemplates:
+                - metadata:
+                    name: data
+                  spec:
+                    storageClassName: fast
+                    resources:
+                      requests:
+                        storage: 2Gi
+                - metadata:
+                    name: datalog
+                  spec:
+                    selector:
+                      matchLabels:
+                        disk: ssd
+                    resources:
+                      requests:
+                        storage: 1Gi
+            ",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn reads_pvc_templates() {
+        let templates = pvc_templates(&statefulset());
+        assert_eq!(
+            templates,
+            vec![
+                PvcTemplate {
+                    name: "data".to_string(),
+                    capacity: Some(Quantity("2Gi".to_string())),
+                    storage_class_name: Some("fast".to_string()),
+                    selector: None,
+                },
+                PvcTemplate {
+                    name: "datalog".to_string(),
+                    capacity: Some(Quantity("1Gi".to_string())),
+                    storage_class_name: None,
+                    selector: Some(LabelSelector {
+                        match_labels: Some([("disk".to_string(), "ssd".to_string())].into()),
+                        ..LabelSelector::default()
+                    }),
+                },
+            ]
+        );
+        assert_eq!(pvc_templates(&StatefulSet::default()), vec![]);
+    }
+
+    #[test]
+    fn compares_templates_by_capacity() {
+        let templates = |data_capacity: &str| {
+            let mut sts = statefulset();
+            sts.spec
+                .as_mut()
+                .unwrap()
+                .volume_claim_templates
+                .as_mut()
+                .unwrap()[0]
+                .spec
+                .as_mut()
+                .unwrap()
+                .resources = Some(ResourceRequirements {
+                requests: Some(
+                    [("storage".to_string(), Quantity(data_capacity.to_string()))].into(),
+                ),
+                ..ResourceRequirements::default()
+            });
+            pvc_templates(&sts)
+        };
+        // The API server canonicalizes quantities
+        assert!(!templates_changed(&templates("1Gi"), &templates("1024Mi")).unwrap());
+        assert!(!templates_changed(&templates("2Gi"), &templates("2Gi")).unwrap());
+        assert!(templates_changed(&templates("1Gi"), &templates("2Gi")).unwrap());
+        assert!(templates_changed(&templates("1Gi")[..1], &templates("1Gi")).unwrap());
+    }
+
+    #[test]
+    fn rejects_storage_class_and_selector_changes() {
+        let existing = pvc_templates(&statefulset());
+        let mut desired = pvc_templates(&statefulset());
+        desired[0].storage_class_name = Some("slow".to_string());
+        assert!(matches!(
+            templates_changed(&existing, &desired),
+            Err(Error::ImmutableVolumeChange {
+                field: "storageClassName",
+                ..
+            })
+        ));
+        let mut desired = pvc_templates(&statefulset());
+        desired[1].selector = None;
+        assert!(matches!(
+            templates_changed(&existing, &desired),
+            Err(Error::ImmutableVolumeChange {
+                field: "selectors",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn matches_claims_to_templates() {
+        let templates = pvc_templates(&statefulset());
+        let template_name = |pvc_name| {
+            claim_template(&templates, "zk-server-default", pvc_name)
+                .map(|template| template.name.as_str())
+        };
+        assert_eq!(template_name("data-zk-server-default-0"), Some("data"));
+        assert_eq!(
+            template_name("datalog-zk-server-default-12"),
+            Some("datalog")
+        );
+        // Claims of other StatefulSets, or that were not created from a template
+        assert_eq!(template_name("data-zk-server-default-big-0"), None);
+        assert_eq!(template_name("data-zk-server-other-0"), None);
+        assert_eq!(template_name("data-zk-server-default-"), None);
+        assert_eq!(template_name("scratch-zk-server-default-0"), None);
+    }
+
+    #[test]
+    fn renders_label_selector() {
+        assert_eq!(
+            label_selector_string(LabelSelector {
+                match_labels: Some(
+                    [
+                        ("app".to_string(), "zookeeper".to_string()),
+                        ("role".to_string(), "server".to_string()),
+                    ]
+                    .into()
+                ),
+                ..LabelSelector::default()
+            }),
+            "app=zookeeper,role=server"
+        );
+        assert_eq!(label_selector_string(LabelSelector::default()), "");
+    }
+}
@@ -0,0 +1,188 @@
+//! ZooKeeper's super user, which bypasses all ACLs
+//!
+//! The operator generates the super user's credentials once for every [`ZookeeperCluster`]. The servers are only given
+//! the digest, which is appended to their `zoo.cfg` when their pods start (see [`super_digest_script`]), so that it never
+//! shows up in their command line or environment. The password is used by the reconfiguration `Job`s, and by the
+//! operator itself to manage ZNodes regardless of their ACLs.
+
+use snafu::{OptionExt, ResultExt, Snafu};
+use stackable_operator::{
+    builder::ObjectMetaBuilder,
+    k8s_openapi::{
+        api::core::v1::{Secret, SecretVolumeSource, Volume},
+        ByteString,
+    },
+    kube::{self, runtime::reflector::ObjectRef, ResourceExt},
+};
+use stackable_zookeeper_crd::ZookeeperCluster;
+
+use crate::zk_controller::FIELD_MANAGER_SCOPE;
+
+pub const SUPER_USER: &str = "super";
+pub const PASSWORD_KEY: &str = "password";
+const DIGEST_KEY: &str = "digest";
+/// Where the super user's credentials are mounted, see [`credentials_volume`]
+pub const CREDENTIALS_DIR: &str = "/stackable/super-credentials";
+pub const CREDENTIALS_VOLUME: &str = "super-credentials";
+
+#[derive(Snafu, Debug)]
+pub enum Error {
+    #[snafu(display("object has no namespace associated"))]
+    NoNamespace,
+    #[snafu(display("object is missing metadata to build owner reference"))]
+    ObjectMissingMetadataForOwnerRef {
+        source: stackable_operator::error::Error,
+    },
+    #[snafu(display("failed to find super user credentials {}", secret))]
+    FindCredentials {
+        source: stackable_operator::error::Error,
+        secret: ObjectRef<Secret>,
+    },
+    #[snafu(display("super user credentials {} are missing the key {}", secret, key))]
+    InvalidCredentials {
+        secret: ObjectRef<Secret>,
+        key: &'static str,
+    },
+    #[snafu(display("failed to generate super user credentials"))]
+    GenerateCredentials { source: openssl::error::ErrorStack },
+    #[snafu(display("failed to save super user credentials to {}", secret))]
+    ApplyCredentials {
+        source: stackable_operator::error::Error,
+        secret: ObjectRef<Secret>,
+    },
+}
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+fn credentials_secret_name(zk: &ZookeeperCluster) -> String {
+    format!("{}-super-credentials", zk.name())
+}
+
+/// The super user's credentials, which the operator uses to manage ZNodes
+pub struct SuperCredentials {
+    password: String,
+}
+
+impl SuperCredentials {
+    /// The `digest` credentials that authenticate a session as the super user
+    pub fn auth(&self) -> String {
+        format!("{}:{}", SUPER_USER, self.password)
+    }
+}
+
+/// Reads the super user's credentials that were generated by [`ensure_super_credentials`]
+pub async fn super_credentials(
+    client: &stackable_operator::client::Client,
+    zk: &ZookeeperCluster,
+) -> Result<SuperCredentials> {
+    let ns = zk.namespace().context(NoNamespaceSnafu)?;
+    let name = credentials_secret_name(zk);
+    let secret_ref = || ObjectRef::<Secret>::new(&name).within(&ns);
+    let secret = client
+        .get::<Secret>(&name, Some(&ns))
+        .await
+        .with_context(|_| FindCredentialsSnafu {
+            secret: secret_ref(),
+        })?;
+    let password = secret
+        .data
+        .unwrap_or_default()
+        .remove(PASSWORD_KEY)
+        .and_then(|value| String::from_utf8(value.0).ok())
+        .with_context(|| InvalidCredentialsSnafu {
+            secret: secret_ref(),
+            key: PASSWORD_KEY,
+        })?;
+    Ok(SuperCredentials { password })
+}
+
+/// Generates the super user's credentials, unless they already exist
+pub async fn ensure_super_credentials(
+    client: &stackable_operator::client::Client,
+    zk: &ZookeeperCluster,
+) -> Result<()> {
+    let ns = zk.namespace().context(NoNamespaceSnafu)?;
+    let name = credentials_secret_name(zk);
+    let secret_ref = || ObjectRef::<Secret>::new(&name).within(&ns);
+    match client.get::<Secret>(&name, Some(&ns)).await {
+        Ok(_) => return Ok(()),
+        Err(stackable_operator::error::Error::KubeError {
+            source: kube::Error::Api(kube::core::ErrorResponse { reason, .. }),
+        }) if reason == "NotFound" => {}
+        Err(err) => {
+            return Err(err).with_context(|_| FindCredentialsSnafu {
+                secret: secret_ref(),
+            })
+        }
+    }
+    tracing::info!(secret = %secret_ref(), "Generating super user credentials");
+    let mut buf = [0; 32];
+    openssl::rand::rand_bytes(&mut buf).context(GenerateCredentialsSnafu)?;
+    let password = buf.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    let secret = Secret {
+        metadata: ObjectMetaBuilder::new()
+            .name_and_namespace(zk)
+            .name(&name)
+            .ownerreference_from_resource(zk, None, Some(true))
+            .context(ObjectMissingMetadataForOwnerRefSnafu)?
+            .build(),
+        data: Some(
+            [
+                (PASSWORD_KEY, password.clone()),
+                (DIGEST_KEY, digest(SUPER_USER, &password)),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), ByteString(v.into_bytes())))
+            .collect(),
+        ),
+        ..Secret::default()
+    };
+    client
+        .apply_patch(FIELD_MANAGER_SCOPE, &secret, &secret)
+        .await
+        .with_context(|_| ApplyCredentialsSnafu {
+            secret: secret_ref(),
+        })?;
+    Ok(())
+}
+
+/// Hashes credentials the same way as ZooKeeper's `DigestAuthenticationProvider`, into `{user}:{base64(sha1)}`
+fn digest(user: &str, password: &str) -> String {
+    let hash = openssl::sha::sha1(format!("{}:{}", user, password).as_bytes());
+    format!("{}:{}", user, openssl::base64::encode_block(&hash))
+}
+
+/// The [`Volume`] that contains the super user's credentials, to be mounted at [`CREDENTIALS_DIR`]
+pub fn credentials_volume(zk: &ZookeeperCluster) -> Volume {
+    Volume {
+        name: CREDENTIALS_VOLUME.to_string(),
+        secret: Some(SecretVolumeSource {
+            secret_name: Some(credentials_secret_name(zk)),
+            ..SecretVolumeSource::default()
+        }),
+        ..Volume::default()
+    }
+}
+
+/// Appends the super user's digest to the server's `zoo_cfg`
+///
+/// ZooKeeper turns any property it does not know into a system property of the same name (prefixed with
+/// `zookeeper.`), so this is equivalent to `-Dzookeeper.DigestAuthenticationProvider.superDigest`.
+pub fn super_digest_script(zoo_cfg: &str) -> String {
+    format!(
+        "printf 'DigestAuthenticationProvider.superDigest=%s\\n' \"$(cat {}/{})\" >> {}",
+        CREDENTIALS_DIR, DIGEST_KEY, zoo_cfg
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digest_matches_zookeeper() {
+        assert_eq!(
+            digest("super", "secret"),
+            "super:lK75jTNcA+U9vtVEw5vB51mj/w4="
+        );
+    }
+}
@@ -10,26 +10,44 @@ use std::{
 
 use crate::{
     discovery::{self, build_discovery_configmaps},
-    APP_NAME, APP_PORT,
+    events::{publish_event, EventType},
+    health,
+    membership::{self, reconcile_membership, Membership},
+    pod_overrides::{self, apply_pod_overrides},
+    restarts::{self, restart_outdated_servers},
+    shutdown::{self, plan_shutdown},
+    status,
+    storage::{self, reconcile_rolegroup_volumes},
+    super_user, ADMIN_PORT, APP_NAME, APP_PORT, APP_SECURE_PORT,
 };
 use fnv::FnvHasher;
-use snafu::{OptionExt, ResultExt, Snafu};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
 use stackable_operator::{
     builder::{ConfigMapBuilder, ContainerBuilder, ObjectMetaBuilder, PodBuilder},
     k8s_openapi::{
         api::{
-            apps::v1::{StatefulSet, StatefulSetSpec},
+            apps::v1::{StatefulSet, StatefulSetSpec, StatefulSetUpdateStrategy},
             core::v1::{
-                ConfigMap, ConfigMapVolumeSource, EnvVar, EnvVarSource, ExecAction,
-                ObjectFieldSelector, PersistentVolumeClaim, PersistentVolumeClaimSpec, Probe,
-                ResourceRequirements, SecurityContext, Service, ServicePort, ServiceSpec, Volume,
+                Affinity, ConfigMap, ConfigMapVolumeSource, EmptyDirVolumeSource, EnvVar,
+                EnvVarSource, ExecAction, KeyToPath, ObjectFieldSelector, PersistentVolumeClaim,
+                PersistentVolumeClaimSpec, PodAffinityTerm, PodAntiAffinity, Probe,
+                ProjectedVolumeSource, ResourceRequirements, Secret, SecretProjection,
+                SecretVolumeSource, SecurityContext, Service, ServicePort, ServiceSpec,
+                TopologySpreadConstraint, Volume, VolumeProjection, WeightedPodAffinityTerm,
             },
+            policy::v1::{PodDisruptionBudget, PodDisruptionBudgetSpec},
+        },
+        apimachinery::pkg::{
+            api::resource::Quantity,
+            apis::meta::v1::{Condition, LabelSelector},
+            util::intstr::IntOrString,
         },
-        apimachinery::pkg::{api::resource::Quantity, apis::meta::v1::LabelSelector},
     },
     kube::{
-        api::ObjectMeta,
+        self,
+        api::{DeleteParams, ObjectMeta},
         runtime::controller::{Context, ReconcilerAction},
+        ResourceExt,
     },
     labels::{role_group_selector_labels, role_selector_labels},
     product_config::{
@@ -38,9 +56,14 @@ use stackable_operator::{
     product_config_utils::{transform_all_roles_to_config, validate_all_roles_and_groups_config},
     role_utils::RoleGroupRef,
 };
-use stackable_zookeeper_crd::{ZookeeperCluster, ZookeeperClusterStatus, ZookeeperRole};
+use stackable_zookeeper_crd::{
+    resources::{PvcConfig, Resources},
+    DigestAuthentication, KerberosAuthentication, TlsSecretRef, VolumeResizeStatus,
+    ZookeeperCluster, ZookeeperClusterStatus, ZookeeperConfig, ZookeeperRole,
+    ZookeeperServerStatus,
+};
 
-const FIELD_MANAGER_SCOPE: &str = "zookeepercluster";
+pub const FIELD_MANAGER_SCOPE: &str = "zookeepercluster";
 
 pub struct Ctx {
     pub client: stackable_operator::client::Client,
@@ -54,6 +77,13 @@ pub enum Error {
     ObjectHasNoNamespace,
     #[snafu(display("object defines no version"))]
     ObjectHasNoVersion,
+    #[snafu(display(
+        "TLS requires ZooKeeper {}.{} or later, since older versions cannot read the store passwords from files (version is {})",
+        MIN_TLS_VERSION.0,
+        MIN_TLS_VERSION.1,
+        version
+    ))]
+    TlsNotSupported { version: String },
     #[snafu(display("object defines no server role"))]
     NoServerRole,
     #[snafu(display("failed to calculate global service name"))]
@@ -66,6 +96,16 @@ pub enum Error {
     ApplyRoleService {
         source: stackable_operator::error::Error,
     },
+    #[snafu(display("failed to build PodDisruptionBudget"))]
+    BuildPodDisruptionBudget {
+        source: stackable_zookeeper_crd::NoNamespaceError,
+    },
+    #[snafu(display("failed to apply PodDisruptionBudget"))]
+    ApplyPodDisruptionBudget {
+        source: stackable_operator::error::Error,
+    },
+    #[snafu(display("failed to delete disabled PodDisruptionBudget"))]
+    DeletePodDisruptionBudget { source: kube::Error },
     #[snafu(display("failed to apply Service for {}", rolegroup))]
     ApplyRoleGroupService {
         source: stackable_operator::error::Error,
@@ -76,16 +116,39 @@ pub enum Error {
         source: stackable_operator::error::Error,
         rolegroup: RoleGroupRef<ZookeeperCluster>,
     },
+    #[snafu(display("failed to find existing StatefulSet for {}", rolegroup))]
+    FindRoleGroupStatefulSet {
+        source: stackable_operator::error::Error,
+        rolegroup: RoleGroupRef<ZookeeperCluster>,
+    },
     #[snafu(display("failed to apply ConfigMap for {}", rolegroup))]
     ApplyRoleGroupConfig {
         source: stackable_operator::error::Error,
         rolegroup: RoleGroupRef<ZookeeperCluster>,
     },
+    #[snafu(display("failed to reconcile volumes for {}", rolegroup))]
+    ReconcileRoleGroupVolumes {
+        source: storage::Error,
+        rolegroup: RoleGroupRef<ZookeeperCluster>,
+    },
+    #[snafu(display("failed to apply podOverrides for {}", rolegroup))]
+    ApplyPodOverrides {
+        source: pod_overrides::Error,
+        rolegroup: RoleGroupRef<ZookeeperCluster>,
+    },
     #[snafu(display("failed to apply StatefulSet for {}", rolegroup))]
     ApplyRoleGroupStatefulSet {
         source: stackable_operator::error::Error,
         rolegroup: RoleGroupRef<ZookeeperCluster>,
     },
+    #[snafu(display("failed to reconcile the membership of the ensemble"))]
+    ReconcileMembership { source: membership::Error },
+    #[snafu(display("failed to ensure the super user's credentials"))]
+    EnsureSuperCredentials { source: super_user::Error },
+    #[snafu(display("failed to stop servers"))]
+    StopServers { source: shutdown::Error },
+    #[snafu(display("failed to restart outdated servers"))]
+    RestartServers { source: restarts::Error },
     #[snafu(display("failed to generate product config"))]
     GenerateProductConfig {
         source: stackable_operator::product_config_utils::ConfigError,
@@ -113,10 +176,73 @@ pub enum Error {
     ApplyStatus {
         source: stackable_operator::error::Error,
     },
+    #[snafu(display("failed to find digest credentials Secret {}", secret))]
+    FindDigestCredentials {
+        source: stackable_operator::error::Error,
+        secret: String,
+    },
+    #[snafu(display(
+        "digest credentials Secret {} contains invalid username {:?}, usernames may only contain alphanumerics, '-', '_' and '.'",
+        secret,
+        username
+    ))]
+    InvalidDigestUsername { secret: String, username: String },
+    #[snafu(display(
+        "configOverrides for {} may not set {:?} in {}, since it is managed by the operator",
+        rolegroup,
+        property,
+        file
+    ))]
+    ForbiddenConfigOverride {
+        rolegroup: RoleGroupRef<ZookeeperCluster>,
+        file: String,
+        property: String,
+    },
 }
 type Result<T, E = Error> = std::result::Result<T, E>;
 
 const PROPERTIES_FILE: &str = "zoo.cfg";
+pub const CLIENT_TLS_DIR: &str = "/stackable/tls/client";
+const QUORUM_TLS_DIR: &str = "/stackable/tls/quorum";
+const DATA_LOG_DIR: &str = "/stackable/datalog";
+/// Where the digest credentials `Secret` is mounted into the prepare container
+const DIGEST_USERS_DIR: &str = "/stackable/digest-users";
+/// The first ZooKeeper version (major, minor) that reads the TLS store passwords from files, see [`tls_store_properties`]
+const MIN_TLS_VERSION: (u64, u64) = (3, 8);
+const HEALTH_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// Pod template annotation that changes whenever the rolegroup's configuration does, see [`config_hash`]
+const CONFIG_HASH_ANNOTATION: &str = "zookeeper.stackable.tech/config-hash";
+/// ZookeeperCluster annotation that requests a restart of all servers, whenever its value changes
+///
+/// Restarts of a single rolegroup can be requested with the annotation `<RESTART_REQUESTED_AT_ANNOTATION>.<rolegroup>`.
+const RESTART_REQUESTED_AT_ANNOTATION: &str = "zookeeper.stackable.tech/restart-requested-at";
+
+/// zoo.cfg properties that are set by the operator, and may not be changed by `configOverrides`
+const OPERATOR_CONTROLLED_PROPERTIES: &[&str] = &[
+    "dataDir",
+    "dataLogDir",
+    "clientPort",
+    "secureClientPort",
+    "serverCnxnFactory",
+    "admin.enableServer",
+    "admin.serverPort",
+    "sslQuorum",
+    "portUnification",
+    "requireClientAuthScheme",
+    "reconfigEnabled",
+    "standaloneEnabled",
+    "dynamicConfigFile",
+];
+/// Prefixes of zoo.cfg properties that are set by the operator, see [`OPERATOR_CONTROLLED_PROPERTIES`]
+const OPERATOR_CONTROLLED_PROPERTY_PREFIXES: &[&str] = &[
+    "server.",
+    "authProvider.",
+    "kerberos.",
+    "ssl.keyStore.",
+    "ssl.trustStore.",
+    "ssl.quorum.keyStore.",
+    "ssl.quorum.trustStore.",
+];
 
 pub async fn reconcile_zk(
     zk: Arc<ZookeeperCluster>,
@@ -125,10 +251,151 @@ pub async fn reconcile_zk(
     tracing::info!("Starting reconcile");
     let client = &ctx.get_ref().client;
 
+    if zk.spec.reconciliation_paused {
+        return reconcile_paused_zk(client, &zk).await;
+    }
+
+    let applied = apply_cluster_resources(client, &zk, &ctx.get_ref().product_config).await;
+    let previous_status = zk.status.clone().unwrap_or_default();
+    let generation = zk.metadata.generation;
+    let status = match &applied {
+        Ok(applied) => {
+            let servers = applied.servers.clone();
+            ZookeeperClusterStatus {
+                discovery_hash: Some(applied.discovery_hash.clone()),
+                volume_resizes: applied.volume_resizes.clone(),
+                observed_generation: generation,
+                conditions: cluster_conditions(&zk, &previous_status.conditions, applied, &servers),
+                leader: health::leader(&servers),
+                servers,
+            }
+        }
+        Err(err) => {
+            let message = status::error_chain(err);
+            // Retries of the same failure would otherwise flood the object with identical events
+            let already_reported = previous_status.conditions.iter().any(|condition| {
+                condition.type_ == status::DEGRADED
+                    && condition.reason == "ReconcileFailed"
+                    && condition.message == message
+            });
+            if !already_reported {
+                publish_event(
+                    client,
+                    &*zk,
+                    EventType::Warning,
+                    "ReconcileFailed",
+                    "Reconcile",
+                    &message,
+                )
+                .await;
+            }
+            // Keep everything else as it was, since we don't know which of the cluster's resources were applied
+            let mut conditions = previous_status.conditions.clone();
+            status::set_condition(
+                &mut conditions,
+                status::condition(
+                    status::DEGRADED,
+                    true,
+                    "ReconcileFailed",
+                    message,
+                    generation,
+                ),
+            );
+            ZookeeperClusterStatus {
+                conditions,
+                ..previous_status
+            }
+        }
+    };
+    let applied_status = client
+        .apply_patch_status(FIELD_MANAGER_SCOPE, &*zk, &status)
+        .await
+        .context(ApplyStatusSnafu);
+    // Report the reconciliation failure rather than the status failure if both failed
+    let applied = applied?;
+    applied_status?;
+
+    Ok(ReconcilerAction {
+        requeue_after: Some(
+            // PersistentVolumeClaims, Pods and Jobs are not watched, so keep polling until they have been resized,
+            // restarted, stopped or reconfigured
+            if applied.statefulsets_pending
+                || !applied.volume_resizes.is_empty()
+                || applied.outdated_servers > 0
+                || applied.stopping_servers > 0
+                || applied.membership_pending
+            {
+                Duration::from_secs(10)
+            } else {
+                // Nothing notifies us when the ensemble's state changes, so refresh it regularly
+                HEALTH_REFRESH_INTERVAL
+            },
+        ),
+    })
+}
+
+/// Only refreshes the status of a [`ZookeeperCluster`] whose reconciliation is paused, without changing anything else
+async fn reconcile_paused_zk(
+    client: &stackable_operator::client::Client,
+    zk: &ZookeeperCluster,
+) -> Result<ReconcilerAction> {
+    tracing::info!("Reconciliation is paused, only updating status");
+    let previous_status = zk.status.clone().unwrap_or_default();
+    let servers = health::server_statuses(zk).await;
+    let mut conditions = previous_status.conditions.clone();
+    status::set_condition(
+        &mut conditions,
+        status::condition(
+            status::RECONCILIATION_PAUSED,
+            true,
+            "ReconciliationPaused",
+            "Reconciliation has been paused with spec.reconciliationPaused",
+            zk.metadata.generation,
+        ),
+    );
+    let status = ZookeeperClusterStatus {
+        conditions,
+        leader: health::leader(&servers),
+        servers,
+        ..previous_status
+    };
+    client
+        .apply_patch_status(FIELD_MANAGER_SCOPE, zk, &status)
+        .await
+        .context(ApplyStatusSnafu)?;
+    Ok(ReconcilerAction {
+        requeue_after: Some(HEALTH_REFRESH_INTERVAL),
+    })
+}
+
+/// The objects that were applied for a [`ZookeeperCluster`]
+struct AppliedClusterResources {
+    discovery_hash: String,
+    volume_resizes: Vec<VolumeResizeStatus>,
+    statefulsets: Vec<StatefulSet>,
+    /// Whether any [`StatefulSet`] could not be applied yet, since it is still being recreated
+    statefulsets_pending: bool,
+    servers: Vec<ZookeeperServerStatus>,
+    /// The number of servers that still need to be restarted to apply their updated configuration
+    outdated_servers: usize,
+    /// The number of servers that are still running, although the cluster has been stopped
+    stopping_servers: usize,
+    /// Whether servers still need to be added to or removed from the ensemble
+    membership_pending: bool,
+    /// Why the last reconfiguration of the ensemble failed, while it is waiting to be retried
+    failed_reconfiguration: Option<String>,
+}
+
+async fn apply_cluster_resources(
+    client: &stackable_operator::client::Client,
+    zk: &ZookeeperCluster,
+    product_config: &ProductConfigManager,
+) -> Result<AppliedClusterResources> {
+    check_tls_supported(zk)?;
     let validated_config = validate_all_roles_and_groups_config(
-        zk_version(&zk)?,
+        zk_version(zk)?,
         &transform_all_roles_to_config(
-            &*zk,
+            zk,
             [(
                 ZookeeperRole::Server.to_string(),
                 (
@@ -136,13 +403,13 @@ pub async fn reconcile_zk(
                         PropertyNameKind::Env,
                         PropertyNameKind::File(PROPERTIES_FILE.to_string()),
                     ],
-                    zk.spec.servers.clone().context(NoServerRoleSnafu)?,
+                    zk.merged_server_role().context(NoServerRoleSnafu)?,
                 ),
             )]
             .into(),
         )
         .context(GenerateProductConfigSnafu)?,
-        &ctx.get_ref().product_config,
+        product_config,
         false,
         false,
     )
@@ -152,7 +419,12 @@ pub async fn reconcile_zk(
         .map(Cow::Borrowed)
         .unwrap_or_default();
 
-    let server_role_service = build_server_role_service(&zk)?;
+    let digest_users = match zk.digest_authentication() {
+        Some(digest) => digest_users(client, zk, digest).await?,
+        None => DigestUsers::default(),
+    };
+
+    let server_role_service = build_server_role_service(zk)?;
     let server_role_service = client
         .apply_patch(
             FIELD_MANAGER_SCOPE,
@@ -161,36 +433,167 @@ pub async fn reconcile_zk(
         )
         .await
         .context(ApplyRoleServiceSnafu)?;
+    match build_server_role_pdb(zk)? {
+        Some(pdb) => {
+            client
+                .apply_patch(FIELD_MANAGER_SCOPE, &pdb, &pdb)
+                .await
+                .context(ApplyPodDisruptionBudgetSnafu)?;
+        }
+        None => {
+            let pdb_enabled = zk
+                .spec
+                .pod_disruption_budget
+                .as_ref()
+                .map_or(true, |config| config.enabled);
+            if delete_server_role_pdb(client, zk).await? && pdb_enabled {
+                publish_event(
+                    client,
+                    zk,
+                    EventType::Warning,
+                    "PodDisruptionBudgetRemoved",
+                    "ApplyPodDisruptionBudget",
+                    "Removed the PodDisruptionBudget, since the ensemble has too few servers to tolerate any disruption",
+                )
+                .await;
+            }
+        }
+    }
+    super_user::ensure_super_credentials(client, zk)
+        .await
+        .context(EnsureSuperCredentialsSnafu)?;
+    let servers = health::server_statuses(zk).await;
+    let membership = reconcile_membership(client, zk, &servers)
+        .await
+        .context(ReconcileMembershipSnafu)?;
+    let shutdown = if zk.spec.stopped.unwrap_or(false) {
+        Some(plan_shutdown(client, zk).await.context(StopServersSnafu)?)
+    } else {
+        None
+    };
+    let mut volume_resizes = Vec::new();
+    let mut statefulsets = Vec::new();
+    let mut statefulsets_pending = false;
     for (rolegroup_name, rolegroup_config) in role_server_config.iter() {
         let rolegroup = zk.server_rolegroup_ref(rolegroup_name);
 
-        let rg_service = build_server_rolegroup_service(&rolegroup, &zk)?;
-        let rg_configmap = build_server_rolegroup_config_map(&rolegroup, &zk, rolegroup_config)?;
-        let rg_statefulset = build_server_rolegroup_statefulset(&rolegroup, &zk, rolegroup_config)?;
+        let rg_service = build_server_rolegroup_service(&rolegroup, zk)?;
+        let rg_configmap =
+            build_server_rolegroup_config_map(&rolegroup, zk, rolegroup_config, &membership)?;
+        let mut rg_statefulset = build_server_rolegroup_statefulset(
+            &rolegroup,
+            zk,
+            rolegroup_config,
+            &rg_configmap,
+            &digest_users,
+        )?;
+        if let Some(spec) = &mut rg_statefulset.spec {
+            if let Some(shutdown) = &shutdown {
+                // Stopped servers are scaled down one at a time rather than all at once
+                spec.replicas = shutdown
+                    .replicas
+                    .get(rolegroup_name)
+                    .copied()
+                    .or(spec.replicas);
+            } else if let Some(&min_replicas) = membership.min_replicas.get(rolegroup_name) {
+                // Removed servers keep running until they have been removed from the ensemble
+                spec.replicas = Some(spec.replicas.unwrap_or(1).max(min_replicas));
+            }
+        }
         client
             .apply_patch(FIELD_MANAGER_SCOPE, &rg_service, &rg_service)
             .await
             .with_context(|_| ApplyRoleGroupServiceSnafu {
                 rolegroup: rolegroup.clone(),
             })?;
+        // Membership changes also rewrite the ConfigMap, so only changes to the configuration hash are reported
+        let previous_config_hash = match client
+            .get::<StatefulSet>(
+                &rolegroup.object_name(),
+                rg_statefulset.metadata.namespace.as_deref(),
+            )
+            .await
+        {
+            // A StatefulSet that is being recreated has already been reported
+            Ok(sts) if sts.metadata.deletion_timestamp.is_none() => pod_template_config_hash(&sts),
+            Ok(_) => None,
+            Err(stackable_operator::error::Error::KubeError {
+                source: kube::Error::Api(kube::core::ErrorResponse { reason, .. }),
+            }) if reason == "NotFound" => None,
+            Err(err) => {
+                return Err(err).with_context(|_| FindRoleGroupStatefulSetSnafu {
+                    rolegroup: rolegroup.clone(),
+                })
+            }
+        };
         client
             .apply_patch(FIELD_MANAGER_SCOPE, &rg_configmap, &rg_configmap)
             .await
             .with_context(|_| ApplyRoleGroupConfigSnafu {
                 rolegroup: rolegroup.clone(),
             })?;
-        client
-            .apply_patch(FIELD_MANAGER_SCOPE, &rg_statefulset, &rg_statefulset)
+        if previous_config_hash.is_some()
+            && previous_config_hash != pod_template_config_hash(&rg_statefulset)
+        {
+            publish_event(
+                client,
+                zk,
+                EventType::Normal,
+                "ConfigChanged",
+                "ApplyConfig",
+                format!("Updated the configuration of {}", rolegroup),
+            )
+            .await;
+        }
+        let volumes = reconcile_rolegroup_volumes(client, &rg_statefulset)
             .await
-            .with_context(|_| ApplyRoleGroupStatefulSetSnafu {
+            .with_context(|_| ReconcileRoleGroupVolumesSnafu {
                 rolegroup: rolegroup.clone(),
             })?;
+        volume_resizes.extend(volumes.resizes);
+        if volumes.recreating_statefulset {
+            publish_event(
+                client,
+                zk,
+                EventType::Normal,
+                "StatefulSetRecreating",
+                "ResizeVolumes",
+                format!(
+                    "Recreating the StatefulSet of {}, since its volumeClaimTemplates have changed",
+                    rolegroup
+                ),
+            )
+            .await;
+        }
+        if volumes.apply_statefulset {
+            statefulsets.push(
+                client
+                    .apply_patch(FIELD_MANAGER_SCOPE, &rg_statefulset, &rg_statefulset)
+                    .await
+                    .with_context(|_| ApplyRoleGroupStatefulSetSnafu {
+                        rolegroup: rolegroup.clone(),
+                    })?,
+            );
+            if !volumes.statefulset_existed {
+                publish_event(
+                    client,
+                    zk,
+                    EventType::Normal,
+                    "StatefulSetCreated",
+                    "ApplyStatefulSet",
+                    format!("Created the StatefulSet of {}", rolegroup),
+                )
+                .await;
+            }
+        } else {
+            statefulsets_pending = true;
+        }
     }
 
     // std's SipHasher is deprecated, and DefaultHasher is unstable across Rust releases.
     // We don't /need/ stability, but it's still nice to avoid spurious changes where possible.
     let mut discovery_hash = FnvHasher::with_key(0);
-    for discovery_cm in build_discovery_configmaps(client, &*zk, &zk, &server_role_service, None)
+    for discovery_cm in build_discovery_configmaps(client, zk, zk, &server_role_service, None)
         .await
         .context(BuildDiscoveryConfigSnafu)?
     {
@@ -203,21 +606,215 @@ pub async fn reconcile_zk(
         }
     }
 
-    let status = ZookeeperClusterStatus {
-        // Serialize as a string to discourage users from trying to parse the value,
-        // and to keep things flexible if we end up changing the hasher at some point.
-        discovery_hash: Some(discovery_hash.finish().to_string()),
-    };
-    client
-        .apply_patch_status(FIELD_MANAGER_SCOPE, &*zk, &status)
+    let outdated_servers = restart_outdated_servers(client, zk, &statefulsets, &servers)
         .await
-        .context(ApplyStatusSnafu)?;
+        .context(RestartServersSnafu)?;
 
-    Ok(ReconcilerAction {
-        requeue_after: None,
+    Ok(AppliedClusterResources {
+        // Serialize as a string to discourage users from trying to parse the value,
+        // and to keep things flexible if we end up changing the hasher at some point.
+        discovery_hash: discovery_hash.finish().to_string(),
+        volume_resizes,
+        statefulsets,
+        statefulsets_pending,
+        servers,
+        outdated_servers,
+        stopping_servers: shutdown.map_or(0, |shutdown| shutdown.running_servers),
+        membership_pending: membership.pending,
+        failed_reconfiguration: membership.failed_reconfiguration,
     })
 }
 
+/// Computes the standard conditions of the cluster from the state of its [`StatefulSet`]s
+fn cluster_conditions(
+    zk: &ZookeeperCluster,
+    previous: &[Condition],
+    applied: &AppliedClusterResources,
+    servers: &[ZookeeperServerStatus],
+) -> Vec<Condition> {
+    let generation = zk.metadata.generation;
+    let stopped = zk.spec.stopped.unwrap_or(false);
+    let server_count = zk.pods().map(|pods| pods.count()).unwrap_or(0);
+    let quorum = server_count / 2 + 1;
+    let ready = applied
+        .statefulsets
+        .iter()
+        .filter_map(|sts| sts.status.as_ref()?.ready_replicas)
+        .sum::<i32>()
+        .max(0) as usize;
+    let readiness = format!("{} of {} servers are ready", ready, server_count);
+    let rolling_out = applied.statefulsets.iter().any(|sts| {
+        let replicas = sts
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.replicas)
+            .unwrap_or(1);
+        match &sts.status {
+            Some(status) => {
+                status.observed_generation < sts.metadata.generation
+                    || status.updated_replicas.unwrap_or(0) < replicas
+                    || status.ready_replicas.unwrap_or(0) < replicas
+            }
+            None => true,
+        }
+    });
+
+    let mut conditions = previous.to_vec();
+    let available = if stopped {
+        status::condition(
+            status::AVAILABLE,
+            false,
+            "Stopped",
+            "The cluster has been stopped",
+            generation,
+        )
+    } else if server_count > 0 && ready >= quorum {
+        status::condition(
+            status::AVAILABLE,
+            true,
+            "QuorumAvailable",
+            readiness.clone(),
+            generation,
+        )
+    } else {
+        status::condition(
+            status::AVAILABLE,
+            false,
+            "QuorumUnavailable",
+            format!("{}, {} are required for a quorum", readiness, quorum),
+            generation,
+        )
+    };
+    let progressing = if applied.statefulsets_pending {
+        status::condition(
+            status::PROGRESSING,
+            true,
+            "RecreatingStatefulSets",
+            "StatefulSets are being recreated to update their volumes",
+            generation,
+        )
+    } else if !applied.volume_resizes.is_empty() {
+        status::condition(
+            status::PROGRESSING,
+            true,
+            "ResizingVolumes",
+            "Volumes are being resized",
+            generation,
+        )
+    } else if stopped && applied.stopping_servers > 0 {
+        status::condition(
+            status::PROGRESSING,
+            true,
+            "Stopping",
+            format!(
+                "{} servers are still running, followers are stopped before the leader",
+                applied.stopping_servers
+            ),
+            generation,
+        )
+    } else if stopped {
+        status::condition(
+            status::PROGRESSING,
+            false,
+            "Stopped",
+            "All servers have been stopped",
+            generation,
+        )
+    } else if applied.membership_pending {
+        status::condition(
+            status::PROGRESSING,
+            true,
+            "Reconfiguring",
+            "Servers are being added to or removed from the ensemble",
+            generation,
+        )
+    } else if server_count > 0 && ready < quorum {
+        status::condition(
+            status::PROGRESSING,
+            true,
+            "WaitingForQuorum",
+            format!("{}, {} are required for a quorum", readiness, quorum),
+            generation,
+        )
+    } else if rolling_out {
+        status::condition(
+            status::PROGRESSING,
+            true,
+            "RollingOut",
+            "Servers are being updated",
+            generation,
+        )
+    } else {
+        status::condition(
+            status::PROGRESSING,
+            false,
+            "RolloutComplete",
+            "All servers are up to date",
+            generation,
+        )
+    };
+    let leaders = servers
+        .iter()
+        .filter(|server| server.mode.as_deref() == Some("leader"))
+        .map(|server| server.pod_name.as_str())
+        .collect::<Vec<_>>();
+    let degraded = if stopped {
+        status::condition(
+            status::DEGRADED,
+            false,
+            "Stopped",
+            "The cluster has been stopped",
+            generation,
+        )
+    } else if leaders.len() > 1 {
+        status::condition(
+            status::DEGRADED,
+            true,
+            "MultipleLeaders",
+            format!(
+                "Several servers consider themselves the leader: {}",
+                leaders.join(", ")
+            ),
+            generation,
+        )
+    } else if let Some(failure) = &applied.failed_reconfiguration {
+        status::condition(
+            status::DEGRADED,
+            true,
+            "ReconfigurationFailed",
+            failure.as_str(),
+            generation,
+        )
+    } else if ready < server_count {
+        status::condition(
+            status::DEGRADED,
+            true,
+            "ServersUnavailable",
+            readiness,
+            generation,
+        )
+    } else {
+        status::condition(
+            status::DEGRADED,
+            false,
+            "AllServersReady",
+            readiness,
+            generation,
+        )
+    };
+    let paused = status::condition(
+        status::RECONCILIATION_PAUSED,
+        false,
+        "ReconciliationActive",
+        "The cluster is being reconciled",
+        generation,
+    );
+    for condition in [available, progressing, degraded, paused] {
+        status::set_condition(&mut conditions, condition);
+    }
+    conditions
+}
+
 /// The server-role service is the primary endpoint that should be used by clients that do not perform internal load balancing,
 /// including targets outside of the cluster.
 ///
@@ -239,7 +836,7 @@ pub fn build_server_role_service(zk: &ZookeeperCluster) -> Result<Service> {
         spec: Some(ServiceSpec {
             ports: Some(vec![ServicePort {
                 name: Some("zk".to_string()),
-                port: APP_PORT.into(),
+                port: zk_client_port(zk).into(),
                 protocol: Some("TCP".to_string()),
                 ..ServicePort::default()
             }]),
@@ -251,27 +848,174 @@ pub fn build_server_role_service(zk: &ZookeeperCluster) -> Result<Service> {
     })
 }
 
+/// The [`PodDisruptionBudget`] keeps node drains from evicting enough servers at once to lose the quorum
+///
+/// Returns `None` if it has been disabled by the administrator, or if the ensemble cannot lose any server (and no
+/// `maxUnavailable` has been set), since a `PodDisruptionBudget` that allows no disruptions would block node drains.
+fn build_server_role_pdb(zk: &ZookeeperCluster) -> Result<Option<PodDisruptionBudget>> {
+    let config = zk.spec.pod_disruption_budget.clone().unwrap_or_default();
+    if !config.enabled {
+        return Ok(None);
+    }
+    let max_unavailable = match config.max_unavailable {
+        Some(max_unavailable) => max_unavailable,
+        None => match zk
+            .server_max_unavailable()
+            .context(BuildPodDisruptionBudgetSnafu)?
+        {
+            0 => return Ok(None),
+            max_unavailable => max_unavailable,
+        },
+    };
+    let role_name = ZookeeperRole::Server.to_string();
+    Ok(Some(PodDisruptionBudget {
+        metadata: ObjectMetaBuilder::new()
+            .name_and_namespace(zk)
+            .name(server_role_pdb_name(zk)?)
+            .ownerreference_from_resource(zk, None, Some(true))
+            .context(ObjectMissingMetadataForOwnerRefSnafu)?
+            .with_recommended_labels(zk, APP_NAME, zk_version(zk)?, &role_name, "global")
+            .build(),
+        spec: Some(PodDisruptionBudgetSpec {
+            max_unavailable: Some(IntOrString::Int(max_unavailable.into())),
+            selector: Some(LabelSelector {
+                match_labels: Some(role_selector_labels(zk, APP_NAME, &role_name)),
+                ..LabelSelector::default()
+            }),
+            ..PodDisruptionBudgetSpec::default()
+        }),
+        status: None,
+    }))
+}
+
+/// Deletes the [`PodDisruptionBudget`] that was created before it was disabled, if any
+///
+/// Returns whether it existed.
+async fn delete_server_role_pdb(
+    client: &stackable_operator::client::Client,
+    zk: &ZookeeperCluster,
+) -> Result<bool> {
+    let ns = zk
+        .metadata
+        .namespace
+        .as_deref()
+        .context(ObjectHasNoNamespaceSnafu)?;
+    match client
+        .get_namespaced_api::<PodDisruptionBudget>(ns)
+        .delete(&server_role_pdb_name(zk)?, &DeleteParams::default())
+        .await
+    {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(kube::core::ErrorResponse { reason, .. })) if reason == "NotFound" => {
+            Ok(false)
+        }
+        Err(err) => Err(err).context(DeletePodDisruptionBudgetSnafu),
+    }
+}
+
+fn server_role_pdb_name(zk: &ZookeeperCluster) -> Result<String> {
+    zk.server_role_service_name()
+        .context(GlobalServiceNameNotFoundSnafu)
+}
+
 /// The rolegroup [`ConfigMap`] configures the rolegroup based on the configuration given by the administrator
 fn build_server_rolegroup_config_map(
     rolegroup: &RoleGroupRef<ZookeeperCluster>,
     zk: &ZookeeperCluster,
     server_config: &HashMap<PropertyNameKind, BTreeMap<String, String>>,
+    membership: &Membership,
 ) -> Result<ConfigMap> {
     let mut zoo_cfg = server_config
         .get(&PropertyNameKind::File(PROPERTIES_FILE.to_string()))
         .cloned()
         .unwrap_or_default();
-    zoo_cfg.extend(zk.pods().into_iter().flatten().map(|pod| {
+    if data_log_pvc(zk, rolegroup).is_some() {
+        zoo_cfg.insert("dataLogDir".to_string(), DATA_LOG_DIR.to_string());
+    }
+    if zk.client_tls().is_some() {
+        // The plaintext port would otherwise still be opened, both by clientPort and by the server.N client address
+        zoo_cfg.remove("clientPort");
+        zoo_cfg.extend([
+            ("secureClientPort".to_string(), APP_SECURE_PORT.to_string()),
+            (
+                "serverCnxnFactory".to_string(),
+                "org.apache.zookeeper.server.NettyServerCnxnFactory".to_string(),
+            ),
+        ]);
+        zoo_cfg.extend(tls_store_properties("ssl", CLIENT_TLS_DIR));
+    }
+    if let Some(tls) = zk.quorum_tls() {
+        zoo_cfg.extend([
+            ("sslQuorum".to_string(), tls.mode.ssl_quorum().to_string()),
+            (
+                "portUnification".to_string(),
+                tls.mode.port_unification().to_string(),
+            ),
+        ]);
+        zoo_cfg.extend(tls_store_properties("ssl.quorum", QUORUM_TLS_DIR));
+    }
+    let mut jaas = None;
+    if zk.sasl_authentication_enabled() {
+        zoo_cfg.extend([
+            (
+                "authProvider.1".to_string(),
+                "org.apache.zookeeper.server.auth.SASLAuthenticationProvider".to_string(),
+            ),
+            ("requireClientAuthScheme".to_string(), "sasl".to_string()),
+        ]);
+        jaas = Some(build_server_jaas_config(zk));
+    }
+    if let Some(kerberos) = zk.kerberos_authentication() {
+        zoo_cfg.extend([
+            (
+                "kerberos.removeHostFromPrincipal".to_string(),
+                kerberos.remove_host_from_principal.to_string(),
+            ),
+            (
+                "kerberos.removeRealmFromPrincipal".to_string(),
+                kerberos.remove_realm_from_principal.to_string(),
+            ),
+        ]);
+    }
+    // The readiness probe and the operator's health checks query the AdminServer
+    zoo_cfg.extend([
+        ("admin.enableServer".to_string(), "true".to_string()),
+        ("admin.serverPort".to_string(), ADMIN_PORT.to_string()),
+    ]);
+    // The members are listed in a separate file, so that they can be changed without restarting the servers
+    zoo_cfg.extend([
+        ("reconfigEnabled".to_string(), "true".to_string()),
+        ("standaloneEnabled".to_string(), "false".to_string()),
         (
-            format!("server.{}", pod.zookeeper_myid),
-            format!("{}:2888:3888;{}", pod.fqdn(), APP_PORT),
-        )
-    }));
+            "dynamicConfigFile".to_string(),
+            format!(
+                "{}/{}",
+                ZookeeperConfig::RW_CONFIG_DIR,
+                membership::DYNAMIC_CONFIG_FILE
+            ),
+        ),
+    ]);
+    for (property, value) in zk.config_overrides(&rolegroup.role_group, PROPERTIES_FILE) {
+        if is_operator_controlled_property(&property) {
+            return ForbiddenConfigOverrideSnafu {
+                rolegroup: rolegroup.clone(),
+                file: PROPERTIES_FILE,
+                property,
+            }
+            .fail();
+        }
+        zoo_cfg.insert(property, value);
+    }
     let zoo_cfg = zoo_cfg
         .into_iter()
         .map(|(k, v)| (k, Some(v)))
         .collect::<Vec<_>>();
-    ConfigMapBuilder::new()
+    let dynamic_cfg = membership::dynamic_config(zk, &membership.members)
+        .into_iter()
+        .map(|(k, v)| (k, Some(v)))
+        .collect::<Vec<_>>();
+    let mut cm_builder = ConfigMapBuilder::new();
+    cm_builder
         .metadata(
             ObjectMetaBuilder::new()
                 .name_and_namespace(zk)
@@ -295,12 +1039,65 @@ fn build_server_rolegroup_config_map(
                 }
             })?,
         )
+        .add_data(
+            membership::DYNAMIC_CONFIG_FILE,
+            to_java_properties_string(dynamic_cfg.iter().map(|(k, v)| (k, v))).with_context(
+                |_| SerializeZooCfgSnafu {
+                    rolegroup: rolegroup.clone(),
+                },
+            )?,
+        );
+    if let Some(jaas) = jaas {
+        cm_builder.add_data(ZookeeperConfig::JAAS_FILE, jaas);
+    }
+    cm_builder
         .build()
         .with_context(|_| BuildRoleGroupConfigSnafu {
             rolegroup: rolegroup.clone(),
         })
 }
 
+fn is_operator_controlled_property(property: &str) -> bool {
+    OPERATOR_CONTROLLED_PROPERTIES.contains(&property)
+        || OPERATOR_CONTROLLED_PROPERTY_PREFIXES
+            .iter()
+            .any(|prefix| property.starts_with(prefix))
+}
+
+/// Renders the JAAS configuration that ZooKeeper uses to authenticate SASL clients
+///
+/// The digest users are not written into the file (since it ends up in a [`ConfigMap`]), instead they are inserted after
+/// each `DigestLoginModule` line when the pod starts, see [`jaas_script`].
+///
+/// With Kerberos enabled every server needs its own principal. The section is shared by all pods anyway, so that it does
+/// not change with the replicas, and each pod fills in its own FQDN for [`KerberosAuthentication::HOST_PLACEHOLDER`].
+fn build_server_jaas_config(zk: &ZookeeperCluster) -> String {
+    let mut digest_module = String::new();
+    if zk.digest_authentication().is_some() {
+        digest_module
+            .push_str("\n    org.apache.zookeeper.server.auth.DigestLoginModule required\n    ;");
+    }
+    match zk.kerberos_authentication() {
+        Some(kerberos) => format!(
+            concat!(
+                "Server {{\n",
+                "    com.sun.security.auth.module.Krb5LoginModule required\n",
+                "    useKeyTab=true\n",
+                "    keyTab=\"{}/{}\"\n",
+                "    storeKey=true\n",
+                "    useTicketCache=false\n",
+                "    principal=\"{}\";{}\n",
+                "}};\n",
+            ),
+            ZookeeperConfig::KEYTAB_DIR,
+            KerberosAuthentication::KEYTAB_KEY,
+            kerberos.server_principal(KerberosAuthentication::HOST_PLACEHOLDER),
+            digest_module
+        ),
+        None => format!("Server {{{}\n}};\n", digest_module),
+    }
+}
+
 /// The rolegroup [`Service`] is a headless service that allows direct access to the instances of a certain rolegroup
 ///
 /// This is mostly useful for internal communication between peers, or for clients that perform client-side load balancing.
@@ -328,7 +1125,7 @@ fn build_server_rolegroup_service(
             ports: Some(vec![
                 ServicePort {
                     name: Some("zk".to_string()),
-                    port: APP_PORT.into(),
+                    port: zk_client_port(zk).into(),
                     protocol: Some("TCP".to_string()),
                     ..ServicePort::default()
                 },
@@ -359,6 +1156,8 @@ fn build_server_rolegroup_statefulset(
     rolegroup_ref: &RoleGroupRef<ZookeeperCluster>,
     zk: &ZookeeperCluster,
     server_config: &HashMap<PropertyNameKind, BTreeMap<String, String>>,
+    rg_configmap: &ConfigMap,
+    digest_users: &DigestUsers,
 ) -> Result<StatefulSet> {
     let rolegroup = zk
         .spec
@@ -367,84 +1166,305 @@ fn build_server_rolegroup_statefulset(
         .context(NoServerRoleSnafu)?
         .role_groups
         .get(&rolegroup_ref.role_group);
+    let config = zk.merged_config(&rolegroup_ref.role_group);
+    let resources = config.resources.unwrap_or_default();
+    let data_log_pvc = data_log_pvc(zk, rolegroup_ref);
     let zk_version = zk_version(zk)?;
     let image = format!(
         "docker.stackable.tech/stackable/zookeeper:{}-stackable0",
         zk_version
     );
-    let env = server_config
+    let mut config_env = server_config
         .get(&PropertyNameKind::Env)
-        .iter()
-        .flat_map(|env_vars| env_vars.iter())
-        .map(|(k, v)| EnvVar {
-            name: k.clone(),
-            value: Some(v.clone()),
-            ..EnvVar::default()
-        })
-        .collect::<Vec<_>>();
-    let mut container_prepare = ContainerBuilder::new("prepare")
+        .cloned()
+        .unwrap_or_default();
+    for (name, value) in zk.env_overrides(&rolegroup_ref.role_group) {
+        if name == ZookeeperConfig::SERVER_JVMFLAGS {
+            // Added to the operator's flags rather than replacing them, since ZooKeeper depends on them (for example for TLS)
+            let jvm_flags = config_env.entry(name).or_default();
+            jvm_flags.push(' ');
+            jvm_flags.push_str(&value);
+        } else {
+            config_env.insert(name, value);
+        }
+    }
+    // Variables that are referenced by the config must come first, since Kubernetes only expands
+    // $(VAR) references to variables that were defined earlier in the list
+    let env = [EnvVar {
+        name: "POD_NAME".to_string(),
+        value_from: Some(EnvVarSource {
+            field_ref: Some(ObjectFieldSelector {
+                api_version: Some("v1".to_string()),
+                field_path: "metadata.name".to_string(),
+            }),
+            ..EnvVarSource::default()
+        }),
+        ..EnvVar::default()
+    }]
+    .into_iter()
+    .chain(config_env.into_iter().map(|(name, value)| EnvVar {
+        name,
+        value: Some(value),
+        ..EnvVar::default()
+    }))
+    .collect::<Vec<_>>();
+    let config_hash = config_hash(rg_configmap, &env);
+    let mut prepare_dirs = vec!["/stackable/data", ZookeeperConfig::RW_CONFIG_DIR];
+    if data_log_pvc.is_some() {
+        prepare_dirs.push(DATA_LOG_DIR);
+    }
+    let mut prepare_script = vec![
+        format!(
+            "cp /stackable/config/zoo.cfg /stackable/config/{} {}/",
+            membership::DYNAMIC_CONFIG_FILE,
+            ZookeeperConfig::RW_CONFIG_DIR
+        ),
+        super_user::super_digest_script(&format!("{}/zoo.cfg", ZookeeperConfig::RW_CONFIG_DIR)),
+    ];
+    if zk.sasl_authentication_enabled() {
+        let pod_fqdn = format!(
+            "$POD_NAME.{}.{}.svc.cluster.local",
+            rolegroup_ref.object_name(),
+            zk.metadata
+                .namespace
+                .as_deref()
+                .context(ObjectHasNoNamespaceSnafu)?
+        );
+        prepare_script.push(jaas_script(digest_users, &pod_fqdn));
+    }
+    let mut container_prepare = ContainerBuilder::new("prepare");
+    container_prepare
         .image(&image)
         .args(vec![
             "sh".to_string(),
             "-c".to_string(),
-            [
-                "chown stackable:stackable /stackable/data",
-                "chmod a=,u=rwX /stackable/data",
-                "expr $MYID_OFFSET + $(echo $POD_NAME | sed 's/.*-//') > /stackable/data/myid",
-            ]
-            .join(" && "),
+            prepare_script
+                .into_iter()
+                .chain([
+                    format!(
+                        "chown stackable:stackable {} {}/*",
+                        prepare_dirs.join(" "),
+                        ZookeeperConfig::RW_CONFIG_DIR
+                    ),
+                    format!("chmod a=,u=rwX {}", prepare_dirs.join(" ")),
+                    "expr $MYID_OFFSET + $(echo $POD_NAME | sed 's/.*-//') > /stackable/data/myid"
+                        .to_string(),
+                ])
+                .collect::<Vec<_>>()
+                .join(" && "),
         ])
         .add_env_vars(env.clone())
-        .add_env_vars(vec![EnvVar {
-            name: "POD_NAME".to_string(),
-            value_from: Some(EnvVarSource {
-                field_ref: Some(ObjectFieldSelector {
-                    api_version: Some("v1".to_string()),
-                    field_path: "metadata.name".to_string(),
-                }),
-                ..EnvVarSource::default()
-            }),
-            ..EnvVar::default()
-        }])
         .add_volume_mount("data", "/stackable/data")
-        .build();
+        .add_volume_mount("config", "/stackable/config")
+        .add_volume_mount("rwconfig", ZookeeperConfig::RW_CONFIG_DIR);
+    if data_log_pvc.is_some() {
+        container_prepare.add_volume_mount("datalog", DATA_LOG_DIR);
+    }
+    let mut pod_builder = PodBuilder::new();
+    container_prepare.add_volume_mount(super_user::CREDENTIALS_VOLUME, super_user::CREDENTIALS_DIR);
+    pod_builder.add_volume(super_user::credentials_volume(zk));
+    if let Some(digest) = zk.digest_authentication() {
+        // Only the prepare container needs the passwords, it writes them into jaas.conf
+        container_prepare.add_volume_mount("digest-users", DIGEST_USERS_DIR);
+        pod_builder.add_volume(Volume {
+            name: "digest-users".to_string(),
+            projected: Some(ProjectedVolumeSource {
+                sources: Some(vec![
+                    VolumeProjection {
+                        secret: Some(SecretProjection {
+                            name: Some(digest.credentials_secret_name.clone()),
+                            ..SecretProjection::default()
+                        }),
+                        ..VolumeProjection::default()
+                    },
+                    VolumeProjection {
+                        secret: Some(SecretProjection {
+                            name: Some(znode_users_secret_name(zk)),
+                            // Only the users that are not configured, so that the paths never collide
+                            items: Some(
+                                digest_users
+                                    .generated
+                                    .iter()
+                                    .map(|user| KeyToPath {
+                                        key: user.clone(),
+                                        path: user.clone(),
+                                        mode: None,
+                                    })
+                                    .collect(),
+                            ),
+                            optional: Some(true),
+                        }),
+                        ..VolumeProjection::default()
+                    },
+                ]),
+                ..ProjectedVolumeSource::default()
+            }),
+            ..Volume::default()
+        });
+    }
+    let mut container_prepare = container_prepare.build();
     container_prepare
         .security_context
         .get_or_insert_with(SecurityContext::default)
         .run_as_user = Some(0);
-    let container_zk = ContainerBuilder::new("zookeeper")
+    let mut container_zk = ContainerBuilder::new("zookeeper");
+    container_zk
         .image(image)
         .args(vec![
             "bin/zkServer.sh".to_string(),
             "start-foreground".to_string(),
-            "/stackable/config/zoo.cfg".to_string(),
+            format!("{}/zoo.cfg", ZookeeperConfig::RW_CONFIG_DIR),
         ])
         .add_env_vars(env)
         // Only allow the global load balancing service to send traffic to pods that are members of the quorum
-        // This also acts as a hint to the StatefulSet controller to wait for each pod to enter quorum before taking down the next
         .readiness_probe(Probe {
             exec: Some(ExecAction {
                 command: Some(vec![
                     "bash".to_string(),
                     "-c".to_string(),
                     // We don't have telnet or netcat in the container images, but
-                    // we can use Bash's virtual /dev/tcp filesystem to accomplish the same thing
+                    // we can use Bash's virtual /dev/tcp filesystem to accomplish the same thing.
+                    // The AdminServer is queried rather than the client port, since the latter may require TLS.
                     format!(
-                        "exec 3<>/dev/tcp/localhost/{} && echo srvr >&3 && grep '^Mode: ' <&3",
-                        APP_PORT
+                        "exec 3<>/dev/tcp/localhost/{} && printf 'GET /commands/srvr HTTP/1.0\\r\\n\\r\\n' >&3 && grep '\"error\" : null' <&3",
+                        ADMIN_PORT
                     ),
                 ]),
             }),
             period_seconds: Some(1),
             ..Probe::default()
         })
-        .add_container_port("zk", APP_PORT.into())
+        .add_container_port("zk", zk_client_port(zk).into())
         .add_container_port("zk-leader", 2888)
         .add_container_port("zk-election", 3888)
         .add_container_port("metrics", 9505)
         .add_volume_mount("data", "/stackable/data")
         .add_volume_mount("config", "/stackable/config")
-        .build();
+        .add_volume_mount("rwconfig", ZookeeperConfig::RW_CONFIG_DIR);
+    if let Some(tls) = zk.client_tls() {
+        container_zk.add_volume_mount("client-tls", CLIENT_TLS_DIR);
+        pod_builder.add_volume(tls_volume("client-tls", tls));
+    }
+    if let Some(tls) = zk.quorum_tls() {
+        container_zk.add_volume_mount("quorum-tls", QUORUM_TLS_DIR);
+        pod_builder.add_volume(tls_volume("quorum-tls", &tls.secret));
+    }
+    if let Some(kerberos) = zk.kerberos_authentication() {
+        container_zk.add_volume_mount("keytab", ZookeeperConfig::KEYTAB_DIR);
+        container_zk.add_volume_mount("krb5-config", ZookeeperConfig::KRB5_CONF_DIR);
+        pod_builder.add_volume(Volume {
+            name: "keytab".to_string(),
+            secret: Some(SecretVolumeSource {
+                secret_name: Some(kerberos.keytab_secret_name.clone()),
+                ..SecretVolumeSource::default()
+            }),
+            ..Volume::default()
+        });
+        pod_builder.add_volume(Volume {
+            name: "krb5-config".to_string(),
+            config_map: Some(ConfigMapVolumeSource {
+                name: Some(kerberos.krb5_config_map_name.clone()),
+                ..ConfigMapVolumeSource::default()
+            }),
+            ..Volume::default()
+        });
+    }
+    if data_log_pvc.is_some() {
+        container_zk.add_volume_mount("datalog", DATA_LOG_DIR);
+    }
+    let mut container_zk = container_zk.build();
+    container_zk.resources = Some(container_resources(&resources));
+    let mut pod_template = pod_builder
+        .metadata_builder(|m| {
+            m.with_recommended_labels(
+                zk,
+                APP_NAME,
+                zk_version,
+                &rolegroup_ref.role,
+                &rolegroup_ref.role_group,
+            )
+        })
+        .add_init_container(container_prepare)
+        .add_container(container_zk)
+        .add_volume(Volume {
+            name: "config".to_string(),
+            config_map: Some(ConfigMapVolumeSource {
+                name: Some(rolegroup_ref.object_name()),
+                ..ConfigMapVolumeSource::default()
+            }),
+            ..Volume::default()
+        })
+        .add_volume(Volume {
+            name: "rwconfig".to_string(),
+            empty_dir: Some(EmptyDirVolumeSource::default()),
+            ..Volume::default()
+        })
+        .build_template();
+    let pod_annotations = pod_template
+        .metadata
+        .get_or_insert_with(ObjectMeta::default)
+        .annotations
+        .get_or_insert_with(BTreeMap::new);
+    pod_annotations.insert(CONFIG_HASH_ANNOTATION.to_string(), config_hash);
+    // Copying the restart requests into the pod template marks the Pods as outdated whenever a new restart is requested
+    let rolegroup_restart_annotation = format!(
+        "{}.{}",
+        RESTART_REQUESTED_AT_ANNOTATION, rolegroup_ref.role_group
+    );
+    for annotation in [
+        RESTART_REQUESTED_AT_ANNOTATION,
+        &rolegroup_restart_annotation,
+    ] {
+        if let Some(requested_at) = zk
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(annotation))
+        {
+            pod_annotations.insert(annotation.to_string(), requested_at.clone());
+        }
+    }
+    if let Some(pod_spec) = &mut pod_template.spec {
+        let role_selector = LabelSelector {
+            match_labels: Some(role_selector_labels(zk, APP_NAME, &rolegroup_ref.role)),
+            ..LabelSelector::default()
+        };
+        pod_spec.affinity = Some(
+            config
+                .affinity
+                .clone()
+                .unwrap_or_else(|| default_server_affinity(role_selector.clone())),
+        );
+        pod_spec.topology_spread_constraints = config.zone_spread.map(|zone_spread| {
+            vec![TopologySpreadConstraint {
+                max_skew: 1,
+                topology_key: "topology.kubernetes.io/zone".to_string(),
+                when_unsatisfiable: zone_spread.when_unsatisfiable().to_string(),
+                label_selector: Some(role_selector),
+            }]
+        });
+        if zk.spec.stopped.unwrap_or(false) {
+            // Servers that are stopped before the leader are recreated as placeholders, see [`shutdown`]
+            pod_spec
+                .node_selector
+                .get_or_insert_with(BTreeMap::new)
+                .insert(
+                    shutdown::STOPPED_NODE_SELECTOR.to_string(),
+                    "true".to_string(),
+                );
+        }
+    }
+    let pod_template = apply_pod_overrides(
+        pod_template,
+        zk.spec
+            .servers
+            .iter()
+            .flat_map(|role| &role.config.config.pod_overrides)
+            .chain(rolegroup.and_then(|rg| rg.config.config.pod_overrides.as_ref())),
+    )
+    .with_context(|_| ApplyPodOverridesSnafu {
+        rolegroup: rolegroup_ref.clone(),
+    })?;
     Ok(StatefulSet {
         metadata: ObjectMetaBuilder::new()
             .name_and_namespace(zk)
@@ -476,58 +1496,447 @@ fn build_server_rolegroup_statefulset(
                 ..LabelSelector::default()
             },
             service_name: rolegroup_ref.object_name(),
-            template: PodBuilder::new()
-                .metadata_builder(|m| {
-                    m.with_recommended_labels(
-                        zk,
-                        APP_NAME,
-                        zk_version,
-                        &rolegroup_ref.role,
-                        &rolegroup_ref.role_group,
-                    )
-                })
-                .add_init_container(container_prepare)
-                .add_container(container_zk)
-                .add_volume(Volume {
-                    name: "config".to_string(),
-                    config_map: Some(ConfigMapVolumeSource {
-                        name: Some(rolegroup_ref.object_name()),
-                        ..ConfigMapVolumeSource::default()
-                    }),
-                    ..Volume::default()
-                })
-                .build_template(),
-            volume_claim_templates: Some(vec![PersistentVolumeClaim {
-                metadata: ObjectMeta {
-                    name: Some("data".to_string()),
-                    ..ObjectMeta::default()
-                },
-                spec: Some(PersistentVolumeClaimSpec {
-                    access_modes: Some(vec!["ReadWriteOnce".to_string()]),
-                    resources: Some(ResourceRequirements {
-                        requests: Some({
-                            let mut map = BTreeMap::new();
-                            map.insert("storage".to_string(), Quantity("1Gi".to_string()));
-                            map
-                        }),
-                        ..ResourceRequirements::default()
-                    }),
-                    ..PersistentVolumeClaimSpec::default()
-                }),
-                ..PersistentVolumeClaim::default()
-            }]),
+            template: pod_template,
+            // Outdated Pods are restarted by the operator instead, so that the quorum is kept, see [`restarts`]
+            update_strategy: Some(StatefulSetUpdateStrategy {
+                type_: Some("OnDelete".to_string()),
+                rolling_update: None,
+            }),
+            volume_claim_templates: Some(
+                [
+                    Some(build_pvc_template(
+                        "data",
+                        resources
+                            .storage
+                            .and_then(|storage| storage.data)
+                            .unwrap_or_default(),
+                    )),
+                    data_log_pvc.map(|pvc| build_pvc_template("datalog", pvc)),
+                ]
+                .into_iter()
+                .flatten()
+                .collect(),
+            ),
             ..StatefulSetSpec::default()
         }),
         status: None,
     })
 }
 
+/// Hashes the rendered configuration of a rolegroup
+///
+/// The [`ConfigMap`] is only referenced by name from the pod template, so changing its contents would not restart the
+/// `Pod`s by itself. Annotating the pod template with this hash marks the `Pod`s as outdated whenever the configuration
+/// actually changes, so that they are restarted (see [`restarts`]). The members of the ensemble are left out, since
+/// they are changed without restarting the servers (see [`membership`]).
+fn config_hash(rg_configmap: &ConfigMap, env: &[EnvVar]) -> String {
+    let mut hasher = FnvHasher::with_key(0);
+    for (key, value) in rg_configmap
+        .data
+        .iter()
+        .flatten()
+        .filter(|(key, _)| *key != membership::DYNAMIC_CONFIG_FILE)
+    {
+        hasher.write(key.as_bytes());
+        hasher.write_u8(0);
+        hasher.write(value.as_bytes());
+        hasher.write_u8(0);
+    }
+    for var in env {
+        hasher.write(var.name.as_bytes());
+        hasher.write_u8(0);
+        hasher.write(var.value.as_deref().unwrap_or_default().as_bytes());
+        hasher.write_u8(0);
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+/// The [`config_hash`] that the pod template of `sts` is annotated with
+fn pod_template_config_hash(sts: &StatefulSet) -> Option<String> {
+    sts.spec
+        .as_ref()
+        .and_then(|spec| spec.template.metadata.as_ref())
+        .and_then(|metadata| metadata.annotations.as_ref())
+        .and_then(|annotations| annotations.get(CONFIG_HASH_ANNOTATION))
+        .cloned()
+}
+
+/// Prefers scheduling the servers of a role onto different nodes, since losing a node that runs several of them could
+/// otherwise cost the ensemble its quorum
+fn default_server_affinity(role_selector: LabelSelector) -> Affinity {
+    Affinity {
+        pod_anti_affinity: Some(PodAntiAffinity {
+            preferred_during_scheduling_ignored_during_execution: Some(vec![
+                WeightedPodAffinityTerm {
+                    weight: 70,
+                    pod_affinity_term: PodAffinityTerm {
+                        label_selector: Some(role_selector),
+                        topology_key: "kubernetes.io/hostname".to_string(),
+                        ..PodAffinityTerm::default()
+                    },
+                },
+            ]),
+            ..PodAntiAffinity::default()
+        }),
+        ..Affinity::default()
+    }
+}
+
+/// The [`ResourceRequirements`] of the `zookeeper` container
+///
+/// Memory is both requested and limited to the same amount, since the JVM heap is sized according to it (see
+/// [`ZookeeperConfig::compute_env`](`stackable_operator::product_config_utils::Configuration::compute_env`)).
+fn container_resources(resources: &Resources) -> ResourceRequirements {
+    let mut requests = BTreeMap::new();
+    let mut limits = BTreeMap::new();
+    if let Some(cpu) = &resources.cpu {
+        if let Some(min) = &cpu.min {
+            requests.insert("cpu".to_string(), min.clone());
+        }
+        if let Some(max) = &cpu.max {
+            limits.insert("cpu".to_string(), max.clone());
+        }
+    }
+    if let Some(limit) = resources
+        .memory
+        .as_ref()
+        .and_then(|memory| memory.limit.as_ref())
+    {
+        requests.insert("memory".to_string(), limit.clone());
+        limits.insert("memory".to_string(), limit.clone());
+    }
+    ResourceRequirements {
+        requests: Some(requests).filter(|requests| !requests.is_empty()),
+        limits: Some(limits).filter(|limits| !limits.is_empty()),
+    }
+}
+
+/// The settings of the dedicated transaction log volume, if the rolegroup has one
+fn data_log_pvc(
+    zk: &ZookeeperCluster,
+    rolegroup: &RoleGroupRef<ZookeeperCluster>,
+) -> Option<PvcConfig> {
+    zk.merged_config(&rolegroup.role_group)
+        .resources?
+        .storage?
+        .data_log
+}
+
+fn build_pvc_template(name: &str, pvc: PvcConfig) -> PersistentVolumeClaim {
+    PersistentVolumeClaim {
+        metadata: ObjectMeta {
+            name: Some(name.to_string()),
+            ..ObjectMeta::default()
+        },
+        spec: Some(PersistentVolumeClaimSpec {
+            access_modes: Some(vec!["ReadWriteOnce".to_string()]),
+            resources: Some(ResourceRequirements {
+                requests: Some(
+                    [(
+                        "storage".to_string(),
+                        pvc.capacity.unwrap_or_else(|| Quantity("1Gi".to_string())),
+                    )]
+                    .into(),
+                ),
+                ..ResourceRequirements::default()
+            }),
+            storage_class_name: pvc.storage_class_name,
+            selector: pvc.selectors,
+            ..PersistentVolumeClaimSpec::default()
+        }),
+        ..PersistentVolumeClaim::default()
+    }
+}
+
+/// The zoo.cfg properties that point ZooKeeper at the stores of a [`TlsSecretRef`] mounted at `dir`
+///
+/// The store password is read from the mounted `Secret` too, so that it never shows up in the configuration or the
+/// server's command line.
+pub fn tls_store_properties(prefix: &str, dir: &str) -> [(String, String); 6] {
+    [
+        (
+            format!("{}.keyStore.location", prefix),
+            format!("{}/{}", dir, TlsSecretRef::KEYSTORE_FILE),
+        ),
+        (
+            format!("{}.keyStore.type", prefix),
+            TlsSecretRef::STORE_TYPE.to_string(),
+        ),
+        (
+            format!("{}.trustStore.location", prefix),
+            format!("{}/{}", dir, TlsSecretRef::TRUSTSTORE_FILE),
+        ),
+        (
+            format!("{}.trustStore.type", prefix),
+            TlsSecretRef::STORE_TYPE.to_string(),
+        ),
+        (
+            format!("{}.keyStore.passwordPath", prefix),
+            format!("{}/{}", dir, TlsSecretRef::PASSWORD_KEY),
+        ),
+        (
+            format!("{}.trustStore.passwordPath", prefix),
+            format!("{}/{}", dir, TlsSecretRef::PASSWORD_KEY),
+        ),
+    ]
+}
+
+/// The `Secret` that the users generated for `ZookeeperZnode`s are registered in, if `zk` uses SASL/Digest authentication
+///
+/// The `Secret` is shared by all `ZookeeperZnode`s of the cluster, which each only manage their own user.
+pub fn znode_users_secret_name(zk: &ZookeeperCluster) -> String {
+    format!("{}-znode-users", zk.name())
+}
+
+/// The users of SASL/Digest authentication, whose passwords are inserted into jaas.conf by [`jaas_script`]
+#[derive(Default)]
+struct DigestUsers {
+    /// The users of the credentials `Secret` of the [`DigestAuthentication`]
+    configured: Vec<String>,
+    /// The users that were generated for `ZookeeperZnode`s (see [`znode_users_secret_name`]), except for any that are
+    /// also configured
+    generated: Vec<String>,
+}
+
+impl DigestUsers {
+    fn all(&self) -> impl Iterator<Item = &String> {
+        self.configured.iter().chain(&self.generated)
+    }
+}
+
+async fn digest_users(
+    client: &stackable_operator::client::Client,
+    zk: &ZookeeperCluster,
+    digest: &DigestAuthentication,
+) -> Result<DigestUsers> {
+    let configured = secret_usernames(client, zk, &digest.credentials_secret_name, false).await?;
+    let generated = secret_usernames(client, zk, &znode_users_secret_name(zk), true)
+        .await?
+        .into_iter()
+        .filter(|user| !configured.contains(user))
+        .collect();
+    Ok(DigestUsers {
+        configured,
+        generated,
+    })
+}
+
+/// Lists the users defined in a digest credentials `Secret`, or none if an `optional` `Secret` does not exist
+///
+/// The passwords themselves are never read by the operator, they are mounted into the server pods instead.
+async fn secret_usernames(
+    client: &stackable_operator::client::Client,
+    zk: &ZookeeperCluster,
+    secret_name: &str,
+    optional: bool,
+) -> Result<Vec<String>> {
+    let secret = match client
+        .get::<Secret>(secret_name, zk.metadata.namespace.as_deref())
+        .await
+    {
+        Ok(secret) => secret,
+        Err(stackable_operator::error::Error::KubeError {
+            source: kube::Error::Api(kube::core::ErrorResponse { reason, .. }),
+        }) if optional && reason == "NotFound" => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err).with_context(|_| FindDigestCredentialsSnafu {
+                secret: secret_name.to_string(),
+            })
+        }
+    };
+    let usernames = secret
+        .data
+        .unwrap_or_default()
+        .into_keys()
+        .collect::<Vec<_>>();
+    if let Some(username) = usernames.iter().find(|username| {
+        !username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    }) {
+        return InvalidDigestUsernameSnafu {
+            secret: secret_name.to_string(),
+            username: username.clone(),
+        }
+        .fail();
+    }
+    Ok(usernames)
+}
+
+/// Prepares the pod's own copy of jaas.conf, see [`build_server_jaas_config`]
+///
+/// The Kerberos principal's host is replaced with `pod_fqdn`, and the `digest_users` are inserted after the
+/// `DigestLoginModule` line. The passwords are escaped for JAAS, and are only ever written into the
+/// pod's own (`emptyDir`) copy of jaas.conf.
+fn jaas_script(digest_users: &DigestUsers, pod_fqdn: &str) -> String {
+    let users_file = format!("{}/digest-users", ZookeeperConfig::RW_CONFIG_DIR);
+    let mut script = format!(": > {}", users_file);
+    for user in digest_users.all() {
+        // Usernames are validated by secret_usernames, so they are safe to use without quoting
+        script.push_str(&format!(
+            " && printf '    user_%s=\"%s\"\\n' {user} \"$(sed 's/[\\\\\"]/\\\\&/g' {dir}/{user})\" >> {file}",
+            user = user,
+            dir = DIGEST_USERS_DIR,
+            file = users_file,
+        ));
+    }
+    // Lines inserted by `r` are not edited, so the substitution can never touch a password
+    script.push_str(&format!(
+        " && sed -e \"/^    principal=/s/{host}/{fqdn}/\" -e '/DigestLoginModule required/r {users}' /stackable/config/{jaas} > {dir}/{jaas} && rm {users}",
+        host = KerberosAuthentication::HOST_PLACEHOLDER,
+        fqdn = pod_fqdn,
+        users = users_file,
+        jaas = ZookeeperConfig::JAAS_FILE,
+        dir = ZookeeperConfig::RW_CONFIG_DIR,
+    ));
+    script
+}
+
+pub fn tls_volume(name: &str, tls: &TlsSecretRef) -> Volume {
+    Volume {
+        name: name.to_string(),
+        secret: Some(SecretVolumeSource {
+            secret_name: Some(tls.secret_name.clone()),
+            ..SecretVolumeSource::default()
+        }),
+        ..Volume::default()
+    }
+}
+
 pub fn zk_version(zk: &ZookeeperCluster) -> Result<&str> {
     zk.spec.version.as_deref().context(ObjectHasNoVersionSnafu)
 }
 
+/// Rejects TLS for ZooKeeper versions that cannot read the store passwords from files
+fn check_tls_supported(zk: &ZookeeperCluster) -> Result<()> {
+    if zk.client_tls().is_none() && zk.quorum_tls().is_none() {
+        return Ok(());
+    }
+    let version = zk_version(zk)?;
+    ensure!(supports_tls(version), TlsNotSupportedSnafu { version });
+    Ok(())
+}
+
+/// Whether `version` is at least [`MIN_TLS_VERSION`], ignoring any patch level or suffix such as `-stackable0`
+fn supports_tls(version: &str) -> bool {
+    match semver::Version::parse(version) {
+        Ok(version) => (version.major, version.minor) >= MIN_TLS_VERSION,
+        Err(_) => false,
+    }
+}
+
+/// The port that clients connect to, which only accepts TLS connections if client TLS is enabled
+pub fn zk_client_port(zk: &ZookeeperCluster) -> u16 {
+    if zk.client_tls().is_some() {
+        APP_SECURE_PORT
+    } else {
+        APP_PORT
+    }
+}
+
 pub fn error_policy(_error: &Error, _ctx: Context<Ctx>) -> ReconcilerAction {
     ReconcilerAction {
         requeue_after: Some(Duration::from_secs(5)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zk(version: &str, tls: bool) -> ZookeeperCluster {
+        let mut zk = serde_json::json!({
+            "apiVersion": "zookeeper.stackable.tech/v1alpha1",
+            "kind": "ZookeeperCluster",
+            "metadata": {"name": "zk", "namespace": "default"},
+            "spec": {
+                "version": version,
+                "servers": {"roleGroups": {"a": {"replicas": 3}}},
+            },
+        });
+        if tls {
+            zk["spec"]["tls"] = serde_json::json!({"client": {"secretName": "zk-client-tls"}});
+        }
+        serde_json::from_value(zk).unwrap()
+    }
+
+    #[test]
+    fn tls_requires_zookeeper_3_8() {
+        assert!(check_tls_supported(&zk("3.5.8", false)).is_ok());
+        assert!(matches!(
+            check_tls_supported(&zk("3.5.8", true)),
+            Err(Error::TlsNotSupported { .. })
+        ));
+        assert!(check_tls_supported(&zk("3.8.0", true)).is_ok());
+        assert!(check_tls_supported(&zk("3.8.0-stackable0", true)).is_ok());
+        assert!(check_tls_supported(&zk("4.0.1", true)).is_ok());
+        assert!(matches!(
+            check_tls_supported(&zk("latest", true)),
+            Err(Error::TlsNotSupported { .. })
+        ));
+    }
+
+    #[test]
+    fn kerberos_jaas_config_does_not_depend_on_the_replicas() {
+        let jaas_config = |replicas: u16| {
+            let zk: ZookeeperCluster = serde_json::from_value(serde_json::json!({
+                "apiVersion": "zookeeper.stackable.tech/v1alpha1",
+                "kind": "ZookeeperCluster",
+                "metadata": {"name": "zk", "namespace": "default"},
+                "spec": {
+                    "version": "3.8.0",
+                    "servers": {"roleGroups": {"a": {"replicas": replicas}}},
+                    "authentication": {
+                        "digest": {"credentialsSecretName": "zk-users"},
+                        "kerberos": {
+                            "realm": "EXAMPLE.COM",
+                            "keytabSecretName": "zk-keytab",
+                            "krb5ConfigMapName": "krb5",
+                        },
+                    },
+                },
+            }))
+            .unwrap();
+            build_server_jaas_config(&zk)
+        };
+        let jaas = jaas_config(3);
+        assert_eq!(jaas, jaas_config(5));
+        assert!(jaas.starts_with("Server {\n"));
+        assert!(jaas.contains("principal=\"zookeeper/_HOST@EXAMPLE.COM\";"));
+        assert!(jaas.contains("DigestLoginModule required"));
+
+        let script = jaas_script(
+            &DigestUsers {
+                configured: vec!["alice".to_string()],
+                generated: vec!["znode-0123".to_string()],
+            },
+            "$POD_NAME.zk-server-a.default.svc.cluster.local",
+        );
+        assert!(script.contains(
+            "sed -e \"/^    principal=/s/_HOST/$POD_NAME.zk-server-a.default.svc.cluster.local/\""
+        ));
+        assert!(script.contains("' alice \"$(sed "));
+        assert!(script.contains("' znode-0123 \"$(sed "));
+        assert!(script.contains("/stackable/digest-users/znode-0123)"));
+    }
+
+    #[test]
+    fn enables_the_admin_server() {
+        let zk = zk("3.8.0", false);
+        let config_map = build_server_rolegroup_config_map(
+            &zk.server_rolegroup_ref("a"),
+            &zk,
+            &HashMap::new(),
+            &Membership {
+                members: [1, 2, 3].into(),
+                min_replicas: BTreeMap::new(),
+                pending: false,
+                failed_reconfiguration: None,
+            },
+        )
+        .unwrap();
+        let zoo_cfg = &config_map.data.unwrap()["zoo.cfg"];
+        assert!(zoo_cfg.contains("admin.enableServer=true\n"));
+        assert!(zoo_cfg.contains(&format!("admin.serverPort={}\n", ADMIN_PORT)));
+    }
+}
@@ -2,15 +2,21 @@
 //!
 //! See [`ZookeeperZnode`] for more details.
 
-use std::{convert::Infallible, sync::Arc, time::Duration};
+use std::{collections::BTreeMap, convert::Infallible, sync::Arc, time::Duration};
 
 use crate::{
     discovery::{self, build_discovery_configmaps},
-    APP_PORT,
+    events::{publish_event, EventType},
+    status, super_user,
+    zk_controller::{zk_client_port, znode_users_secret_name},
 };
 use snafu::{OptionExt, ResultExt, Snafu};
 use stackable_operator::{
-    k8s_openapi::api::core::v1::{ConfigMap, Service},
+    builder::ObjectMetaBuilder,
+    k8s_openapi::{
+        api::core::v1::{ConfigMap, Secret, Service},
+        ByteString,
+    },
     kube::{
         self,
         api::ObjectMeta,
@@ -21,7 +27,10 @@ use stackable_operator::{
         },
     },
 };
-use stackable_zookeeper_crd::{ZookeeperCluster, ZookeeperZnode};
+use stackable_zookeeper_crd::{
+    TlsSecretRef, ZnodeAcl, ZnodeAclScheme, ZnodePermission, ZookeeperCluster, ZookeeperZnode,
+    ZookeeperZnodeStatus,
+};
 
 const FIELD_MANAGER_SCOPE: &str = "zookeeperznode";
 
@@ -56,6 +65,11 @@ pub enum Error {
     },
     #[snafu(display("failed to calculate FQDN for {}", zk))]
     NoZkFqdn { zk: ObjectRef<ZookeeperCluster> },
+    #[snafu(display("failed to find the operator's credentials for {}", zk))]
+    OperatorCredentials {
+        source: super_user::Error,
+        zk: ObjectRef<ZookeeperCluster>,
+    },
     #[snafu(display("failed to ensure that ZNode {} exists in {}", znode_path, zk))]
     EnsureZnode {
         source: znode_mgmt::Error,
@@ -83,6 +97,56 @@ pub enum Error {
     ObjectMissingMetadataForOwnerRef {
         source: stackable_operator::error::Error,
     },
+    #[snafu(display("failed to find credentials {}", secret))]
+    FindCredentials {
+        source: stackable_operator::error::Error,
+        secret: ObjectRef<Secret>,
+    },
+    #[snafu(display(
+        "{} already exists, but does not belong to {}, so it is neither used nor replaced",
+        secret,
+        znode
+    ))]
+    CredentialsNotOwned {
+        secret: ObjectRef<Secret>,
+        znode: ObjectRef<ZookeeperZnode>,
+    },
+    #[snafu(display("credentials {} are missing the key {}", secret, key))]
+    InvalidCredentials {
+        secret: ObjectRef<Secret>,
+        key: &'static str,
+    },
+    #[snafu(display("failed to generate credentials"))]
+    GenerateCredentials { source: openssl::error::ErrorStack },
+    #[snafu(display("failed to save credentials to {}", secret))]
+    ApplyCredentials {
+        source: stackable_operator::error::Error,
+        secret: ObjectRef<Secret>,
+    },
+    #[snafu(display("failed to find client TLS stores {}", secret))]
+    FindClientTls {
+        source: stackable_operator::error::Error,
+        secret: ObjectRef<Secret>,
+    },
+    #[snafu(display("client TLS stores {} are missing the key {}", secret, key))]
+    InvalidClientTls {
+        secret: ObjectRef<Secret>,
+        key: &'static str,
+    },
+    #[snafu(display("failed to read client TLS stores {}", secret))]
+    ReadClientTls {
+        source: znode_mgmt::Error,
+        secret: ObjectRef<Secret>,
+    },
+    #[snafu(display("failed to register the generated user in {}", secret))]
+    ApplyZnodeUser {
+        source: stackable_operator::error::Error,
+        secret: ObjectRef<Secret>,
+    },
+    #[snafu(display("failed to update status"))]
+    ApplyStatus {
+        source: stackable_operator::error::Error,
+    },
 }
 type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -121,26 +185,90 @@ pub async fn reconcile_znode(
     };
     let client = &ctx.get_ref().client;
 
+    if znode.spec.reconciliation_paused {
+        tracing::info!("Reconciliation is paused, only updating status");
+        let mut status = znode.status.clone().unwrap_or_default();
+        status::set_condition(
+            &mut status.conditions,
+            status::condition(
+                status::RECONCILIATION_PAUSED,
+                true,
+                "ReconciliationPaused",
+                "Reconciliation has been paused with spec.reconciliationPaused",
+                znode.metadata.generation,
+            ),
+        );
+        client
+            .apply_patch_status(FIELD_MANAGER_SCOPE, &*znode, &status)
+            .await
+            .context(ApplyStatusSnafu)?;
+        return Ok(ReconcilerAction {
+            requeue_after: None,
+        });
+    }
+
     let zk = find_zk_of_znode(client, &znode).await;
     // Use the uid (managed by k8s itself) rather than the object name, to ensure that malicious users can't trick the controller
     // into letting them take over a znode owned by someone else
     let znode_path = format!("/znode-{}", uid);
 
-    finalizer(
+    let result = finalizer(
         &client.get_namespaced_api::<ZookeeperZnode>(&ns),
         "zookeeper.stackable.tech/znode",
-        znode,
+        znode.clone(),
         |ev| async {
             match ev {
                 finalizer::Event::Apply(znode) => {
                     reconcile_apply(client, &znode, zk, &znode_path).await
                 }
-                finalizer::Event::Cleanup(_znode) => reconcile_cleanup(zk, &znode_path).await,
+                finalizer::Event::Cleanup(znode) => {
+                    reconcile_cleanup(client, &znode, zk, &znode_path).await
+                }
             }
         },
     )
     .await
-    .map_err(Error::extract_finalizer_err)
+    .map_err(Error::extract_finalizer_err);
+    if let Err(err) = &result {
+        let message = status::error_chain(err);
+        // Retries of the same failure would otherwise flood the object with identical events
+        if znode
+            .status
+            .as_ref()
+            .and_then(|status| status.last_error.as_ref())
+            != Some(&message)
+        {
+            publish_event(
+                client,
+                &*znode,
+                EventType::Warning,
+                "ReconcileFailed",
+                "Reconcile",
+                &message,
+            )
+            .await;
+        }
+        // Successful reconciliations update the status themselves, since only they know the discovery details
+        let mut status = znode.status.clone().unwrap_or_default();
+        status::set_condition(
+            &mut status.conditions,
+            status::condition(
+                status::READY,
+                false,
+                "ReconcileFailed",
+                message.clone(),
+                znode.metadata.generation,
+            ),
+        );
+        status.last_error = Some(message);
+        if let Err(status_err) = client
+            .apply_patch_status(FIELD_MANAGER_SCOPE, &*znode, &status)
+            .await
+        {
+            tracing::warn!(error = %status_err, "Failed to report reconciliation failure in status");
+        }
+    }
+    result
 }
 
 async fn reconcile_apply(
@@ -150,12 +278,70 @@ async fn reconcile_apply(
     znode_path: &str,
 ) -> Result<ReconcilerAction> {
     let zk = zk?;
-    znode_mgmt::ensure_znode_exists(&zk_mgmt_addr(&zk)?, znode_path)
+    let mut acls = znode.spec.acls.clone();
+    let credentials = if znode.spec.generate_credentials {
+        // Save the credentials before using them, so that they can never be lost
+        Some(ensure_credentials(client, znode).await?)
+    } else {
+        None
+    };
+    let sasl_digest = zk.digest_authentication().is_some();
+    if let Some(credentials) = &credentials {
+        acls.extend(credentials.acls(sasl_digest));
+    }
+    apply_znode_user(
+        client,
+        znode,
+        &zk,
+        credentials.as_ref().filter(|_| sasl_digest),
+    )
+    .await?;
+    let operator_credentials = super_user::super_credentials(client, &zk)
         .await
-        .with_context(|_| EnsureZnodeSnafu {
+        .with_context(|_| OperatorCredentialsSnafu {
             zk: ObjectRef::from_obj(&zk),
-            znode_path,
         })?;
+    let znode_change = znode_mgmt::ensure_znode_exists(
+        &zk_mgmt_addr(&zk)?,
+        &operator_credentials,
+        zk_mgmt_tls(client, &zk).await?.as_ref(),
+        znode_path,
+        &acls,
+    )
+    .await
+    .with_context(|_| EnsureZnodeSnafu {
+        zk: ObjectRef::from_obj(&zk),
+        znode_path,
+    })?;
+    match znode_change {
+        znode_mgmt::ZnodeChange::Created => {
+            publish_event(
+                client,
+                znode,
+                EventType::Normal,
+                "ZnodeCreated",
+                "CreateZnode",
+                format!(
+                    "Created ZNode {} in {}",
+                    znode_path,
+                    ObjectRef::from_obj(&zk)
+                ),
+            )
+            .await
+        }
+        znode_mgmt::ZnodeChange::UpdatedAcl => {
+            publish_event(
+                client,
+                znode,
+                EventType::Normal,
+                "ZnodeAclUpdated",
+                "UpdateZnodeAcl",
+                format!("Updated ACL of ZNode {}", znode_path),
+            )
+            .await
+        }
+        znode_mgmt::ZnodeChange::Unchanged => {}
+    }
 
     let server_role_service = client
         .get::<Service>(
@@ -169,6 +355,7 @@ async fn reconcile_apply(
         .context(FindZkSvcSnafu {
             zk: ObjectRef::from_obj(&zk),
         })?;
+    let mut discovery_config_maps = Vec::new();
     for discovery_cm in
         build_discovery_configmaps(client, znode, &zk, &server_role_service, Some(znode_path))
             .await
@@ -180,14 +367,57 @@ async fn reconcile_apply(
             .with_context(|_| ApplyDiscoveryConfigMapSnafu {
                 cm: ObjectRef::from_obj(&discovery_cm),
             })?;
+        discovery_config_maps.extend(discovery_cm.metadata.name);
     }
 
+    let mut conditions = znode
+        .status
+        .as_ref()
+        .map(|status| status.conditions.clone())
+        .unwrap_or_default();
+    status::set_condition(
+        &mut conditions,
+        status::condition(
+            status::READY,
+            true,
+            "ZnodeReady",
+            format!(
+                "ZNode {} exists in {}",
+                znode_path,
+                ObjectRef::from_obj(&zk)
+            ),
+            znode.metadata.generation,
+        ),
+    );
+    status::set_condition(
+        &mut conditions,
+        status::condition(
+            status::RECONCILIATION_PAUSED,
+            false,
+            "ReconciliationActive",
+            "The ZNode is being reconciled",
+            znode.metadata.generation,
+        ),
+    );
+    let status = ZookeeperZnodeStatus {
+        znode_path: Some(znode_path.to_string()),
+        discovery_config_maps,
+        conditions,
+        last_error: None,
+    };
+    client
+        .apply_patch_status(FIELD_MANAGER_SCOPE, znode, &status)
+        .await
+        .context(ApplyStatusSnafu)?;
+
     Ok(ReconcilerAction {
         requeue_after: None,
     })
 }
 
 async fn reconcile_cleanup(
+    client: &stackable_operator::client::Client,
+    znode: &ZookeeperZnode,
     zk: Result<ZookeeperCluster>,
     znode_path: &str,
 ) -> Result<ReconcilerAction> {
@@ -200,30 +430,316 @@ async fn reconcile_cleanup(
         }
         res => res?,
     };
-    // Clean up znode from the ZooKeeper cluster before letting Kubernetes delete the object
-    znode_mgmt::ensure_znode_missing(&zk_mgmt_addr(&zk)?, znode_path)
+    let operator_credentials = super_user::super_credentials(client, &zk)
         .await
-        .with_context(|_| EnsureZnodeMissingSnafu {
+        .with_context(|_| OperatorCredentialsSnafu {
             zk: ObjectRef::from_obj(&zk),
-            znode_path,
         })?;
+    apply_znode_user(client, znode, &zk, None).await?;
+    // Clean up znode from the ZooKeeper cluster before letting Kubernetes delete the object
+    znode_mgmt::ensure_znode_missing(
+        &zk_mgmt_addr(&zk)?,
+        &operator_credentials,
+        zk_mgmt_tls(client, &zk).await?.as_ref(),
+        znode_path,
+    )
+    .await
+    .with_context(|_| EnsureZnodeMissingSnafu {
+        zk: ObjectRef::from_obj(&zk),
+        znode_path,
+    })?;
+    publish_event(
+        client,
+        znode,
+        EventType::Normal,
+        "ZnodeDeleted",
+        "DeleteZnode",
+        format!(
+            "Deleted ZNode {} from {}",
+            znode_path,
+            ObjectRef::from_obj(&zk)
+        ),
+    )
+    .await;
     // No need to delete the ConfigMap, since that has an OwnerReference on the ZookeeperZnode object
     Ok(ReconcilerAction {
         requeue_after: None,
     })
 }
 
+/// Digest credentials that are granted access to a [`ZookeeperZnode`]'s chroot
+struct ZnodeCredentials {
+    username: String,
+    password: String,
+}
+
+impl ZnodeCredentials {
+    const USERNAME_KEY: &'static str = "username";
+    const PASSWORD_KEY: &'static str = "password";
+    const JAAS_KEY: &'static str = "jaas.conf";
+
+    fn generate() -> Result<Self> {
+        Ok(Self {
+            username: format!("znode-{}", random_hex(8)?),
+            password: random_hex(32)?,
+        })
+    }
+
+    /// Reads the credentials from the `znode`'s own [`Secret`]
+    ///
+    /// A [`Secret`] that only happens to have the same name (but is not owned by the `znode`) is rejected, rather than
+    /// exposing its contents or overwriting it.
+    fn from_secret(secret: &Secret, znode: &ZookeeperZnode) -> Result<Self> {
+        let owned = secret
+            .metadata
+            .owner_references
+            .iter()
+            .flatten()
+            .any(|owner| Some(&owner.uid) == znode.metadata.uid.as_ref());
+        if !owned {
+            return CredentialsNotOwnedSnafu {
+                secret: ObjectRef::from_obj(secret),
+                znode: ObjectRef::from_obj(znode),
+            }
+            .fail();
+        }
+        let data = secret.data.as_ref();
+        let get = |key| {
+            data.and_then(|data| data.get(key))
+                .and_then(|value| String::from_utf8(value.0.clone()).ok())
+                .with_context(|| InvalidCredentialsSnafu {
+                    secret: ObjectRef::from_obj(secret),
+                    key,
+                })
+        };
+        Ok(Self {
+            username: get(Self::USERNAME_KEY)?,
+            password: get(Self::PASSWORD_KEY)?,
+        })
+    }
+
+    /// The ACL entries granting these credentials full access
+    ///
+    /// Clients can always authenticate using the `digest` scheme (`addauth digest {username}:{password}`), which does not
+    /// require any configuration on the servers. The id is hashed the same way as by ZooKeeper's
+    /// `DigestAuthenticationProvider`. If the cluster uses SASL/Digest authentication (`sasl_digest`), the user is
+    /// registered with the servers (see [`apply_znode_user`]) and granted access as a `sasl` user as well.
+    fn acls(&self, sasl_digest: bool) -> Vec<ZnodeAcl> {
+        let hash = openssl::sha::sha1(format!("{}:{}", self.username, self.password).as_bytes());
+        let acl = |scheme, id| ZnodeAcl {
+            scheme,
+            id,
+            permissions: vec![
+                ZnodePermission::Read,
+                ZnodePermission::Write,
+                ZnodePermission::Create,
+                ZnodePermission::Delete,
+                ZnodePermission::Admin,
+            ],
+        };
+        let mut acls = vec![acl(
+            ZnodeAclScheme::Digest,
+            format!("{}:{}", self.username, openssl::base64::encode_block(&hash)),
+        )];
+        if sasl_digest {
+            acls.push(acl(ZnodeAclScheme::Sasl, self.username.clone()));
+        }
+        acls
+    }
+
+    /// A JAAS configuration that authenticates the ZooKeeper client over SASL/Digest with these credentials
+    fn client_jaas_config(&self) -> String {
+        let escape = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+        format!(
+            concat!(
+                "Client {{\n",
+                "    org.apache.zookeeper.server.auth.DigestLoginModule required\n",
+                "    username=\"{}\"\n",
+                "    password=\"{}\";\n",
+                "}};\n",
+            ),
+            escape(&self.username),
+            escape(&self.password)
+        )
+    }
+}
+
+fn random_hex(bytes: usize) -> Result<String> {
+    let mut buf = vec![0; bytes];
+    openssl::rand::rand_bytes(&mut buf).context(GenerateCredentialsSnafu)?;
+    Ok(buf.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Loads the generated credentials of a [`ZookeeperZnode`], generating them if they don't exist yet
+///
+/// The credentials are saved in a [`Secret`] next to the discovery [`ConfigMap`]s, which is owned by the [`ZookeeperZnode`].
+async fn ensure_credentials(
+    client: &stackable_operator::client::Client,
+    znode: &ZookeeperZnode,
+) -> Result<ZnodeCredentials> {
+    let name = znode
+        .metadata
+        .name
+        .as_deref()
+        .context(ObjectMissingMetadataSnafu)?;
+    let ns = znode.metadata.namespace.as_deref();
+    let secret_ref = || ObjectRef::<Secret>::new(name).within(ns.unwrap_or_default());
+    let credentials = match client.get::<Secret>(name, ns).await {
+        Ok(secret) => ZnodeCredentials::from_secret(&secret, znode)?,
+        Err(stackable_operator::error::Error::KubeError {
+            source: kube::Error::Api(kube::core::ErrorResponse { reason, .. }),
+        }) if reason == "NotFound" => {
+            tracing::info!(secret = %secret_ref(), "Generating credentials");
+            ZnodeCredentials::generate()?
+        }
+        Err(err) => {
+            return Err(err).with_context(|_| FindCredentialsSnafu {
+                secret: secret_ref(),
+            })
+        }
+    };
+    let secret = Secret {
+        metadata: ObjectMetaBuilder::new()
+            .name_and_namespace(znode)
+            .ownerreference_from_resource(znode, None, Some(true))
+            .context(ObjectMissingMetadataForOwnerRefSnafu)?
+            .build(),
+        data: Some(
+            [
+                (ZnodeCredentials::USERNAME_KEY, credentials.username.clone()),
+                (ZnodeCredentials::PASSWORD_KEY, credentials.password.clone()),
+                (ZnodeCredentials::JAAS_KEY, credentials.client_jaas_config()),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), ByteString(v.into_bytes())))
+            .collect::<BTreeMap<_, _>>(),
+        ),
+        ..Secret::default()
+    };
+    client
+        .apply_patch(FIELD_MANAGER_SCOPE, &secret, &secret)
+        .await
+        .with_context(|_| ApplyCredentialsSnafu {
+            secret: secret_ref(),
+        })?;
+    Ok(credentials)
+}
+
+/// Registers the generated `credentials` as a SASL/Digest user with the servers of `zk`, or unregisters them if `None`
+///
+/// The users of all `ZookeeperZnode`s share one `Secret` (see [`znode_users_secret_name`]), which is applied with a
+/// separate field manager for each `ZookeeperZnode`. Every `ZookeeperZnode` therefore only ever adds or removes its own
+/// user, and the servers pick up the change in their next rolling restart.
+async fn apply_znode_user(
+    client: &stackable_operator::client::Client,
+    znode: &ZookeeperZnode,
+    zk: &ZookeeperCluster,
+    credentials: Option<&ZnodeCredentials>,
+) -> Result<()> {
+    let name = znode_users_secret_name(zk);
+    let ns = zk.metadata.namespace.as_deref();
+    let secret_ref = || ObjectRef::<Secret>::new(&name).within(ns.unwrap_or_default());
+    if credentials.is_none() {
+        // Nothing to unregister from, so don't create the Secret just to leave it empty
+        match client.get::<Secret>(&name, ns).await {
+            Ok(_) => {}
+            Err(stackable_operator::error::Error::KubeError {
+                source: kube::Error::Api(kube::core::ErrorResponse { reason, .. }),
+            }) if reason == "NotFound" => return Ok(()),
+            Err(err) => {
+                return Err(err).with_context(|_| ApplyZnodeUserSnafu {
+                    secret: secret_ref(),
+                })
+            }
+        }
+    }
+    let secret = Secret {
+        metadata: ObjectMetaBuilder::new()
+            .name_and_namespace(zk)
+            .name(&name)
+            .ownerreference_from_resource(zk, None, Some(true))
+            .context(ObjectMissingMetadataForOwnerRefSnafu)?
+            .build(),
+        data: credentials.map(|credentials| {
+            [(
+                credentials.username.clone(),
+                ByteString(credentials.password.clone().into_bytes()),
+            )]
+            .into()
+        }),
+        ..Secret::default()
+    };
+    let field_manager = format!(
+        "{}/{}",
+        FIELD_MANAGER_SCOPE,
+        znode
+            .metadata
+            .uid
+            .as_deref()
+            .context(ObjectMissingMetadataSnafu)?
+    );
+    client
+        .apply_patch(&field_manager, &secret, &secret)
+        .await
+        .with_context(|_| ApplyZnodeUserSnafu {
+            secret: secret_ref(),
+        })?;
+    Ok(())
+}
+
+/// The connection string for managing the ZNodes of `zk`, which lists all servers like the discovery `ConfigMap`s do
 fn zk_mgmt_addr(zk: &ZookeeperCluster) -> Result<String> {
-    // Rust ZooKeeper client does not support client-side load-balancing, so use
-    // (load-balanced) global service instead.
-    Ok(format!(
-        "{}:{}",
-        zk.server_role_service_fqdn()
-            .with_context(|| NoZkFqdnSnafu {
-                zk: ObjectRef::from_obj(zk),
-            })?,
-        APP_PORT,
-    ))
+    Ok(zk
+        .pods()
+        .ok()
+        .with_context(|| NoZkFqdnSnafu {
+            zk: ObjectRef::from_obj(zk),
+        })?
+        .map(|pod| format!("{}:{}", pod.fqdn(), zk_client_port(zk)))
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+/// Reads the key material that the operator connects to `zk` with, if it requires client TLS
+///
+/// The operator uses the same stores as the servers, see [`TlsSecretRef`].
+async fn zk_mgmt_tls(
+    client: &stackable_operator::client::Client,
+    zk: &ZookeeperCluster,
+) -> Result<Option<znode_mgmt::ClientTls>> {
+    let tls = match zk.client_tls() {
+        Some(tls) => tls,
+        None => return Ok(None),
+    };
+    let secret_ref = || {
+        ObjectRef::<Secret>::new(&tls.secret_name)
+            .within(zk.metadata.namespace.as_deref().unwrap_or_default())
+    };
+    let secret = client
+        .get::<Secret>(&tls.secret_name, zk.metadata.namespace.as_deref())
+        .await
+        .with_context(|_| FindClientTlsSnafu {
+            secret: secret_ref(),
+        })?;
+    let data = secret.data.unwrap_or_default();
+    let get = |key| {
+        data.get(key)
+            .map(|value| value.0.as_slice())
+            .with_context(|| InvalidClientTlsSnafu {
+                secret: secret_ref(),
+                key,
+            })
+    };
+    let password = String::from_utf8_lossy(get(TlsSecretRef::PASSWORD_KEY)?);
+    znode_mgmt::ClientTls::from_pkcs12(
+        get(TlsSecretRef::KEYSTORE_FILE)?,
+        get(TlsSecretRef::TRUSTSTORE_FILE)?,
+        password.trim_end_matches('\n'),
+    )
+    .map(Some)
+    .with_context(|_| ReadClientTlsSnafu {
+        secret: secret_ref(),
+    })
 }
 
 async fn find_zk_of_znode(
@@ -260,114 +776,209 @@ pub fn error_policy(_error: &Error, _ctx: Context<Ctx>) -> ReconcilerAction {
 }
 
 mod znode_mgmt {
-    use futures::compat::Future01CompatExt;
+    use crate::super_user::SuperCredentials;
+    use openssl::{pkcs12::Pkcs12, x509::X509Ref};
     use snafu::{OptionExt, ResultExt, Snafu};
-    use std::{collections::VecDeque, net::SocketAddr};
-    use tokio::net::lookup_host;
-    use tokio_zookeeper::{Acl, Permission, ZooKeeper};
+    use stackable_zookeeper_crd::{ZnodeAcl, ZnodePermission};
+    use std::collections::VecDeque;
+    use zookeeper_client as zk;
 
     #[derive(Snafu, Debug)]
     pub enum Error {
-        #[snafu(display("invalid address {}", addr))]
-        InvalidAddr {
-            source: std::io::Error,
-            addr: String,
-        },
-        #[snafu(display("address {} did not resolve to any socket addresses", addr))]
-        AddrResolution { addr: String },
+        #[snafu(display("failed to read keystore"))]
+        ReadKeystore { source: openssl::error::ErrorStack },
+        #[snafu(display("keystore contains no private key and certificate"))]
+        KeystoreHasNoKey,
+        #[snafu(display("failed to read truststore"))]
+        ReadTruststore { source: openssl::error::ErrorStack },
+        #[snafu(display("failed to configure TLS"))]
+        ConfigureTls { source: zk::Error },
         #[snafu(display("failed to connect to {}", addr))]
-        Connect {
-            #[snafu(source(from(failure::Error, failure::Error::compat)))]
-            source: failure::Compat<failure::Error>,
-            addr: SocketAddr,
-        },
-        #[snafu(display("protocol error creating znode {}", path))]
-        CreateZnodeProtocol {
-            #[snafu(source(from(failure::Error, failure::Error::compat)))]
-            source: failure::Compat<failure::Error>,
-            path: String,
-        },
+        Connect { source: zk::Error, addr: String },
         #[snafu(display("failed to create znode {}", path))]
-        CreateZnode {
-            #[snafu(source(from(tokio_zookeeper::error::Create, failure::Fail::compat)))]
-            source: failure::Compat<tokio_zookeeper::error::Create>,
-            path: String,
-        },
-        #[snafu(display("protocol error deleting znode {}", path))]
-        DeleteZnodeProtocol {
-            #[snafu(source(from(failure::Error, failure::Error::compat)))]
-            source: failure::Compat<failure::Error>,
-            path: String,
-        },
+        CreateZnode { source: zk::Error, path: String },
+        #[snafu(display("failed to get ACL of znode {}", path))]
+        GetZnodeAcl { source: zk::Error, path: String },
+        #[snafu(display("failed to set ACL of znode {}", path))]
+        SetZnodeAcl { source: zk::Error, path: String },
         #[snafu(display("failed to delete znode {}", path))]
-        DeleteZnode {
-            #[snafu(source(from(tokio_zookeeper::error::Delete, failure::Fail::compat)))]
-            source: failure::Compat<tokio_zookeeper::error::Delete>,
-            path: String,
-        },
+        DeleteZnode { source: zk::Error, path: String },
         #[snafu(display("failed to find children to delete of {}", path))]
-        DeleteZnodeFindChildrenProtocol {
-            #[snafu(source(from(failure::Error, failure::Error::compat)))]
-            source: failure::Compat<failure::Error>,
-            path: String,
-        },
+        DeleteZnodeFindChildren { source: zk::Error, path: String },
+    }
+
+    /// The key material that the operator connects to clusters that require client TLS with, in PEM format
+    pub struct ClientTls {
+        ca_certs: String,
+        cert_chain: String,
+        key: String,
+    }
+
+    impl ClientTls {
+        /// Converts the PKCS#12 stores of a [`TlsSecretRef`](`stackable_zookeeper_crd::TlsSecretRef`), which are
+        /// meant for Java, into the PEM that the client expects
+        pub fn from_pkcs12(
+            keystore: &[u8],
+            truststore: &[u8],
+            password: &str,
+        ) -> Result<Self, Error> {
+            let keystore = Pkcs12::from_der(keystore)
+                .and_then(|keystore| keystore.parse2(password))
+                .context(ReadKeystoreSnafu)?;
+            let truststore = Pkcs12::from_der(truststore)
+                .and_then(|truststore| truststore.parse2(password))
+                .context(ReadTruststoreSnafu)?;
+            let to_pem = |certs: &mut dyn Iterator<Item = &X509Ref>| {
+                certs
+                    .map(|cert| {
+                        cert.to_pem()
+                            .map(|pem| String::from_utf8_lossy(&pem).into_owned())
+                    })
+                    .collect::<Result<String, _>>()
+            };
+            let (key, cert) = keystore
+                .pkey
+                .zip(keystore.cert)
+                .context(KeystoreHasNoKeySnafu)?;
+            Ok(Self {
+                // Trusted certificates have no key, so they are all listed as CA certificates
+                ca_certs: to_pem(
+                    &mut truststore
+                        .ca
+                        .iter()
+                        .flatten()
+                        .chain(truststore.cert.as_deref()),
+                )
+                .context(ReadTruststoreSnafu)?,
+                cert_chain: to_pem(
+                    &mut std::iter::once(&*cert).chain(keystore.ca.iter().flatten()),
+                )
+                .context(ReadKeystoreSnafu)?,
+                key: String::from_utf8_lossy(
+                    &key.private_key_to_pem_pkcs8().context(ReadKeystoreSnafu)?,
+                )
+                .into_owned(),
+            })
+        }
+
+        fn tls_options(&self) -> Result<zk::TlsOptions, Error> {
+            zk::TlsOptions::no_ca()
+                .with_pem_ca_certs(&self.ca_certs)
+                .and_then(|options| options.with_pem_identity(&self.cert_chain, &self.key))
+                .context(ConfigureTlsSnafu)
+        }
     }
 
-    async fn connect(addr: &str) -> Result<ZooKeeper, Error> {
+    /// Connects to ZooKeeper, authenticated as the super user
+    ///
+    /// The super user bypasses all ACLs, so the operator can manage any ZNode regardless of the ACL it was given.
+    async fn connect(
+        addr: &str,
+        credentials: &SuperCredentials,
+        tls: Option<&ClientTls>,
+    ) -> Result<zk::Client, Error> {
         tracing::debug!(addr, "Connecting to ZooKeeper");
-        // TODO: Happy eyeballs?
-        let addr = lookup_host(addr)
-            .await
-            .context(InvalidAddrSnafu { addr })?
-            .next()
-            .context(AddrResolutionSnafu { addr })?;
-        let (zk, _) = ZooKeeper::connect(&addr)
-            .compat()
+        let mut connector = zk::Client::connector();
+        connector.auth("digest".to_string(), credentials.auth().into_bytes());
+        let addr = match tls {
+            Some(tls) => {
+                connector.tls(tls.tls_options()?);
+                format!("tcp+tls://{}", addr)
+            }
+            None => addr.to_string(),
+        };
+        let zk = connector
+            .connect(&addr)
             .await
-            .context(ConnectSnafu { addr })?;
+            .context(ConnectSnafu { addr: &addr })?;
         tracing::debug!("Connected to ZooKeeper");
         Ok(zk)
     }
 
-    #[tracing::instrument]
+    /// Translates the ACL of a [`ZookeeperZnode`](`stackable_zookeeper_crd::ZookeeperZnode`) into ZooKeeper's representation
+    ///
+    /// An empty ACL grants everyone full access.
+    fn zk_acls(acls: &[ZnodeAcl]) -> Vec<zk::Acl> {
+        if acls.is_empty() {
+            return vec![zk::Acl::new(zk::Permission::ALL, "world", "anyone")];
+        }
+        acls.iter()
+            .map(|acl| {
+                zk::Acl::new(
+                    acl.permissions
+                        .iter()
+                        .map(|perm| match perm {
+                            ZnodePermission::Read => zk::Permission::READ,
+                            ZnodePermission::Write => zk::Permission::WRITE,
+                            ZnodePermission::Create => zk::Permission::CREATE,
+                            ZnodePermission::Delete => zk::Permission::DELETE,
+                            ZnodePermission::Admin => zk::Permission::ADMIN,
+                        })
+                        .fold(zk::Permission::NONE, |perms, perm| perms | perm),
+                    acl.scheme.to_string(),
+                    acl.id.clone(),
+                )
+            })
+            .collect()
+    }
+
+    /// The changes that [`ensure_znode_exists`] had to make
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum ZnodeChange {
+        Created,
+        UpdatedAcl,
+        Unchanged,
+    }
+
+    #[tracing::instrument(skip(credentials, tls))]
     /// Creates a znode, and ensure that any metadata (such as ACLs) match the desired state
-    pub async fn ensure_znode_exists(addr: &str, path: &str) -> Result<(), Error> {
+    pub async fn ensure_znode_exists(
+        addr: &str,
+        credentials: &SuperCredentials,
+        tls: Option<&ClientTls>,
+        path: &str,
+        acls: &[ZnodeAcl],
+    ) -> Result<ZnodeChange, Error> {
         tracing::info!(znode = path, "Creating ZNode");
-        let zk = connect(addr).await?;
-        let (_zk, create_res) = zk
-            .create(
-                path,
-                vec![],
-                vec![Acl {
-                    perms: Permission::ALL,
-                    scheme: "world".to_string(),
-                    id: "anyone".to_string(),
-                }],
-                tokio_zookeeper::CreateMode::Persistent,
-            )
-            .compat()
-            .await
-            .context(CreateZnodeProtocolSnafu { path })?;
-        match create_res {
+        let acls = zk_acls(acls);
+        let zk = connect(addr, credentials, tls).await?;
+        let options = zk::CreateMode::Persistent.with_acls(zk::Acls::new(&acls));
+        match zk.create(path, &[], &options).await {
             Ok(_) => {
-                tracing::info!(znode = "Created ZNode");
-                Ok(())
+                tracing::info!(znode = path, "Created ZNode");
+                Ok(ZnodeChange::Created)
             }
-            Err(tokio_zookeeper::error::Create::NodeExists) => {
-                tracing::info!(znode = "ZNode already exists, ignoring...");
-                Ok(())
+            Err(zk::Error::NodeExists) => {
+                tracing::info!(znode = path, "ZNode already exists, checking ACL...");
+                let (current_acls, _) =
+                    zk.get_acl(path).await.context(GetZnodeAclSnafu { path })?;
+                if current_acls == acls {
+                    tracing::info!(znode = path, "ZNode ACL is up to date");
+                    Ok(ZnodeChange::Unchanged)
+                } else {
+                    tracing::info!(znode = path, ?current_acls, ?acls, "Updating ZNode ACL");
+                    zk.set_acl(path, &acls, None)
+                        .await
+                        .context(SetZnodeAclSnafu { path })?;
+                    Ok(ZnodeChange::UpdatedAcl)
+                }
             }
             Err(err) => Err(err).context(CreateZnodeSnafu { path }),
         }
     }
 
-    #[tracing::instrument]
+    #[tracing::instrument(skip(credentials, tls))]
     /// Deletes a znode recursively
     ///
     /// Returns `Ok` if the znode could not be found (for idempotence).
-    pub async fn ensure_znode_missing(addr: &str, path: &str) -> Result<(), Error> {
+    pub async fn ensure_znode_missing(
+        addr: &str,
+        credentials: &SuperCredentials,
+        tls: Option<&ClientTls>,
+        path: &str,
+    ) -> Result<(), Error> {
         tracing::info!(znode = path, "Deleting ZNode");
-        let mut zk = connect(addr).await?;
+        let zk = connect(addr, credentials, tls).await?;
         let mut queue = VecDeque::new();
         queue.push_front(path.to_string());
         while let Some(curr_path) = queue.pop_front() {
@@ -376,40 +987,31 @@ mod znode_mgmt {
                 ?queue,
                 "Deleting ZNode from queue"
             );
-            let (zk2, children) = zk
-                .get_children(&curr_path)
-                .compat()
-                .await
-                .context(DeleteZnodeFindChildrenProtocolSnafu { path: &curr_path })?;
-            zk = zk2;
-            match children {
-                None => {
+            match zk.list_children(&curr_path).await {
+                Err(zk::Error::NoNode) => {
                     tracing::warn!(
                         znode = curr_path.as_str(),
                         "ZNode could not be found, assuming it has already been deleted..."
                     );
                 }
-                Some(children) if children.is_empty() => {
+                Err(err) => {
+                    return Err(err).context(DeleteZnodeFindChildrenSnafu { path: &curr_path })
+                }
+                Ok(children) if children.is_empty() => {
                     tracing::info!(
                         znode = curr_path.as_str(),
                         "ZNode has no children, deleting..."
                     );
-                    let (zk2, delete_res) = zk
-                        .delete(&curr_path, None)
-                        .compat()
-                        .await
-                        .context(DeleteZnodeProtocolSnafu { path: &curr_path })?;
-                    zk = zk2;
-                    match delete_res {
-                        Ok(_) => tracing::info!(znode = curr_path.as_str(), "Deleted ZNode"),
-                        Err(tokio_zookeeper::error::Delete::NoNode) => tracing::info!(
+                    match zk.delete(&curr_path, None).await {
+                        Ok(()) => tracing::info!(znode = curr_path.as_str(), "Deleted ZNode"),
+                        Err(zk::Error::NoNode) => tracing::info!(
                             znode = curr_path.as_str(),
                             "ZNode couldn't be found, assuming it has already been deleted..."
                         ),
-                        Err(err) => return Err(err).context(DeleteZnodeSnafu { path }),
+                        Err(err) => return Err(err).context(DeleteZnodeSnafu { path: &curr_path }),
                     }
                 }
-                Some(children) => {
+                Ok(children) => {
                     tracing::info!(
                         znode = curr_path.as_str(),
                         ?children,
@@ -424,4 +1026,226 @@ mod znode_mgmt {
         }
         Ok(())
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use openssl::{
+            asn1::Asn1Time,
+            hash::MessageDigest,
+            pkey::{PKey, Private},
+            rsa::Rsa,
+            stack::Stack,
+            x509::{X509Builder, X509NameBuilder, X509},
+        };
+        use stackable_zookeeper_crd::ZnodeAclScheme;
+
+        fn self_signed() -> (PKey<Private>, X509) {
+            let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+            let mut name = X509NameBuilder::new().unwrap();
+            name.append_entry_by_text("CN", "zk").unwrap();
+            let name = name.build();
+            let mut cert = X509Builder::new().unwrap();
+            cert.set_subject_name(&name).unwrap();
+            cert.set_issuer_name(&name).unwrap();
+            cert.set_pubkey(&key).unwrap();
+            cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+                .unwrap();
+            cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+                .unwrap();
+            cert.sign(&key, MessageDigest::sha256()).unwrap();
+            (key, cert.build())
+        }
+
+        #[test]
+        fn converts_pkcs12_stores_to_pem() {
+            let (key, cert) = self_signed();
+            let keystore = Pkcs12::builder()
+                .name("zk")
+                .pkey(&key)
+                .cert(&cert)
+                .build2("changeit")
+                .unwrap();
+            let mut trusted = Stack::new().unwrap();
+            trusted.push(cert.clone()).unwrap();
+            let truststore = Pkcs12::builder().ca(trusted).build2("changeit").unwrap();
+
+            let tls = ClientTls::from_pkcs12(
+                &keystore.to_der().unwrap(),
+                &truststore.to_der().unwrap(),
+                "changeit",
+            )
+            .unwrap();
+            let cert_pem = String::from_utf8(cert.to_pem().unwrap()).unwrap();
+            assert_eq!(tls.ca_certs, cert_pem);
+            assert_eq!(tls.cert_chain, cert_pem);
+            assert_eq!(tls.key.as_bytes(), key.private_key_to_pem_pkcs8().unwrap());
+
+            assert!(matches!(
+                ClientTls::from_pkcs12(
+                    &keystore.to_der().unwrap(),
+                    &truststore.to_der().unwrap(),
+                    "wrong"
+                ),
+                Err(Error::ReadKeystore { .. })
+            ));
+            assert!(matches!(
+                ClientTls::from_pkcs12(
+                    &truststore.to_der().unwrap(),
+                    &truststore.to_der().unwrap(),
+                    "changeit"
+                ),
+                Err(Error::KeystoreHasNoKey)
+            ));
+        }
+
+        #[test]
+        fn empty_acl_grants_everyone_access() {
+            assert_eq!(
+                zk_acls(&[]),
+                vec![zk::Acl::new(zk::Permission::ALL, "world", "anyone")]
+            );
+        }
+
+        #[test]
+        fn maps_schemes_and_permissions() {
+            let acls = [
+                ZnodeAcl {
+                    scheme: ZnodeAclScheme::Sasl,
+                    id: "alice".to_string(),
+                    permissions: vec![ZnodePermission::Read, ZnodePermission::Write],
+                },
+                ZnodeAcl {
+                    scheme: ZnodeAclScheme::Ip,
+                    id: "10.0.0.0/8".to_string(),
+                    permissions: vec![
+                        ZnodePermission::Create,
+                        ZnodePermission::Delete,
+                        ZnodePermission::Admin,
+                    ],
+                },
+                ZnodeAcl {
+                    scheme: ZnodeAclScheme::X509,
+                    id: "CN=bob".to_string(),
+                    permissions: vec![],
+                },
+            ];
+            assert_eq!(
+                zk_acls(&acls),
+                vec![
+                    zk::Acl::new(
+                        zk::Permission::READ | zk::Permission::WRITE,
+                        "sasl",
+                        "alice"
+                    ),
+                    zk::Acl::new(
+                        zk::Permission::CREATE | zk::Permission::DELETE | zk::Permission::ADMIN,
+                        "ip",
+                        "10.0.0.0/8"
+                    ),
+                    zk::Acl::new(zk::Permission::NONE, "x509", "CN=bob"),
+                ]
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn znode() -> ZookeeperZnode {
+        serde_yaml::from_str(
+            "
+            apiVersion: zookeeper.stackable.tech/v1alpha1
+            kind: ZookeeperZnode
+            metadata:
+              name: znode
+              namespace: default
+              uid: c0ffee
+            spec:
+              clusterRef:
+                name: zk
+            ",
+        )
+        .unwrap()
+    }
+
+    fn secret(owner_uid: Option<&str>) -> Secret {
+        let mut secret: Secret = serde_yaml::from_str(
+            "
+            apiVersion: v1
+            kind: Secret
+            metadata:
+              name: znode
+              namespace: default
+            data:
+              username: em5vZGUtMDEyMw==
+              password: c2VjcmV0
+            ",
+        )
+        .unwrap();
+        if let Some(uid) = owner_uid {
+            secret.metadata.owner_references = Some(vec![serde_yaml::from_str(&format!(
+                "{{apiVersion: zookeeper.stackable.tech/v1alpha1, kind: ZookeeperZnode, name: znode, uid: {}}}",
+                uid
+            ))
+            .unwrap()]);
+        }
+        secret
+    }
+
+    #[test]
+    fn reads_credentials_from_own_secret() {
+        let credentials = ZnodeCredentials::from_secret(&secret(Some("c0ffee")), &znode()).unwrap();
+        assert_eq!(credentials.username, "znode-0123");
+        assert_eq!(credentials.password, "secret");
+    }
+
+    #[test]
+    fn rejects_secret_of_someone_else() {
+        for secret in [secret(None), secret(Some("other"))] {
+            assert!(matches!(
+                ZnodeCredentials::from_secret(&secret, &znode()),
+                Err(Error::CredentialsNotOwned { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn credentials_acl_uses_zookeeper_digest() {
+        let credentials = ZnodeCredentials {
+            username: "znode-0123".to_string(),
+            password: "secret".to_string(),
+        };
+        let acls = credentials.acls(false);
+        assert_eq!(acls.len(), 1);
+        assert_eq!(acls[0].scheme, ZnodeAclScheme::Digest);
+        assert_eq!(acls[0].id, "znode-0123:PGRwaqrRYoBnpfpZQ6l50Q6O/Yo=");
+        assert_eq!(acls[0].permissions.len(), 5);
+
+        let acls = credentials.acls(true);
+        assert_eq!(acls.len(), 2);
+        assert_eq!(acls[1].scheme, ZnodeAclScheme::Sasl);
+        assert_eq!(acls[1].id, "znode-0123");
+        assert_eq!(acls[1].permissions, acls[0].permissions);
+    }
+
+    #[test]
+    fn credentials_jaas_config_logs_in_over_sasl_digest() {
+        let credentials = ZnodeCredentials {
+            username: "znode-0123".to_string(),
+            password: "se\"cr\\et".to_string(),
+        };
+        assert_eq!(
+            credentials.client_jaas_config(),
+            concat!(
+                "Client {\n",
+                "    org.apache.zookeeper.server.auth.DigestLoginModule required\n",
+                "    username=\"znode-0123\"\n",
+                "    password=\"se\\\"cr\\\\et\";\n",
+                "};\n",
+            )
+        );
+    }
 }